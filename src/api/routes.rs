@@ -5,32 +5,166 @@ use axum::{
     routing::get,
     Router,
 };
-use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::services::p2p::TwapMessage;
+use crate::config::{
+    Config, DEFAULT_CANDLES, DEFAULT_ENTRIES_LIMIT, MAX_CANDLES, MAX_ENTRIES_LIMIT,
+    MAX_ENTRIES_SCANNED,
+};
+use crate::services::aggregation;
+use crate::services::aggregation::{AggregationMode, BreakdownKey, Contribution};
+use crate::services::consensus::{Consensus, ConsensusService, SignatureBundle};
+use crate::services::indexer::{IndexerStatus, SharedIndexerState};
+use crate::services::p2p::{P2PCommand, TwapMessage};
+use crate::services::pricing::{
+    Breakdown, PricedWindow, PricingError, PricingService, TwapMetadata, WindowEnd,
+};
+use crate::services::redis_client::{EntryCursor, SpotEntryFilter};
+use crate::services::signing::SigningScheme;
 use crate::services::{redis_client::RedisClient, SigningService};
+use crate::types::candle::{Candle, Resolution};
+use crate::types::pair_info::PairInfo;
+use crate::types::spot_entry::SpotEntry;
+use crate::types::twap_payload::TwapPayload;
 
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
     status: String,
+    network: String,
     redis_connection: bool,
+    indexer_status: IndexerStatus,
+    indexer_head_block: Option<u64>,
+    indexer_lag: Option<u64>,
+    indexer_last_error: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TwapQuery {
     pair_id: String,
     period: Option<u64>, // period in seconds, optional with default
+    aggregation: Option<AggregationMode>, // defaults to twap
+    end_time: Option<u64>, // historical window ending at this timestamp
+    end_block: Option<u64>, // historical window ending at this block
 }
 
 #[derive(Debug, Serialize)]
 pub struct TwapResponse {
+    #[serde(flatten)]
+    payload: TwapPayload,
+    derived: bool,
+    signed_message: String,
+    message_hash: String,
+    scheme: SigningScheme,
+    signature: String,
+    public_key: String,
+    metadata: TwapMetadata,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignedTwapResponse {
+    #[serde(flatten)]
+    payload: TwapPayload,
+    derived: bool,
+    signed_message: String,
+    #[serde(flatten)]
+    signatures: SignatureBundle,
+    metadata: TwapMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CandlesQuery {
+    pair_id: String,
+    resolution: Resolution,
+    start_time: Option<u64>, // defaults to DEFAULT_CANDLES candles before end_time
+    end_time: Option<u64>,   // defaults to now
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandlesResponse {
+    network: String,
+    pair_id: String,
+    resolution: Resolution,
+    decimals: u32,
+    start_time: u64,
+    end_time: u64,
+    candles: Vec<Candle>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntriesQuery {
+    pair_id: String,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+    source: Option<String>,
+    publisher: Option<String>,
+    cursor: Option<String>, // next_cursor of the previous page
+    limit: Option<usize>,   // defaults to DEFAULT_ENTRIES_LIMIT
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntriesResponse {
+    network: String,
+    pair_id: String,
+    entries: Vec<SpotEntry>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairSummary {
+    #[serde(flatten)]
+    info: PairInfo,
+    decimals: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairsResponse {
+    network: String,
+    pairs: Vec<PairSummary>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BreakdownQuery {
+    pair_id: String,
+    period: Option<u64>,
+    aggregation: Option<AggregationMode>,
+    group_by: Option<BreakdownKey>, // defaults to source
+    end_time: Option<u64>,
+    end_block: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakdownResponse {
+    network: String,
     pair_id: String,
-    twap: String,
     period: u64,
-    signature: String,
+    window_start: u64,
+    window_end: u64,
+    end_block: Option<u64>,
+    aggregation: AggregationMode,
+    group_by: BreakdownKey,
+    decimals: u32,
+    aggregate: String,
+    contributions: Vec<Contribution>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsensusQuery {
+    pair_id: String,
+    period: Option<u64>,
+    aggregation: Option<AggregationMode>,
+    end_time: Option<u64>,
+    end_block: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConsensusResponse {
+    network: String,
+    #[serde(flatten)]
+    consensus: Consensus,
 }
 
 #[derive(Debug, Serialize)]
@@ -39,18 +173,30 @@ pub struct ErrorResponse {
 }
 
 pub struct ApiState {
+    pub config: Config,
     pub redis_client: RedisClient,
+    pub indexer_state: SharedIndexerState,
+    pub pricing: PricingService,
+    pub consensus: ConsensusService,
     pub signing_service: SigningService,
-    pub p2p_sender: UnboundedSender<TwapMessage>,
+    pub p2p_sender: UnboundedSender<P2PCommand>,
 }
 
 pub fn create_router(
+    config: Config,
     redis_client: RedisClient,
+    indexer_state: SharedIndexerState,
+    pricing: PricingService,
+    consensus: ConsensusService,
     signing_service: SigningService,
-    p2p_sender: UnboundedSender<TwapMessage>,
+    p2p_sender: UnboundedSender<P2PCommand>,
 ) -> Router {
     let state = Arc::new(ApiState {
+        config,
         redis_client,
+        indexer_state,
+        pricing,
+        consensus,
         signing_service,
         p2p_sender,
     });
@@ -58,6 +204,12 @@ pub fn create_router(
     Router::new()
         .route("/health", get(health_check))
         .route("/api/get_data", get(get_twap))
+        .route("/api/get_signed_data", get(get_signed_twap))
+        .route("/api/candles", get(get_candles))
+        .route("/api/entries", get(get_entries))
+        .route("/api/pairs", get(get_pairs))
+        .route("/api/breakdown", get(get_breakdown))
+        .route("/api/consensus", get(get_consensus))
         .with_state(state)
 }
 
@@ -65,9 +217,20 @@ async fn health_check(State(state): State<Arc<ApiState>>) -> Json<HealthResponse
     // Check Redis connection
     let redis_status = state.redis_client.check_connection().await.is_ok();
 
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let indexer = state.indexer_state.read().await.clone();
+
     Json(HealthResponse {
         status: "up".to_string(),
+        network: state.config.network.name().to_string(),
         redis_connection: redis_status,
+        indexer_status: indexer.status_at(now, state.config.indexer_max_lag),
+        indexer_head_block: indexer.head_block,
+        indexer_lag: indexer.lag(now),
+        indexer_last_error: indexer.last_error,
     })
 }
 
@@ -76,29 +239,16 @@ async fn get_twap(
     Query(params): Query<TwapQuery>,
 ) -> Result<Json<TwapResponse>, (StatusCode, Json<ErrorResponse>)> {
     let period = params.period.unwrap_or(3600); // Default to 1 hour
+    let aggregation = params.aggregation.unwrap_or_default();
+    let end = window_end(params.end_time, params.end_block).map_err(pricing_error)?;
     println!("{}", params.pair_id);
-    let twap_ = state
-        .redis_client
-        .compute_twap(&params.pair_id, period)
+    let PricedWindow { payload, metadata } = state
+        .pricing
+        .price(&params.pair_id, period, aggregation, end)
         .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to compute TWAP: {}", e),
-                }),
-            )
-        })?
-        .ok_or_else(|| {
-            (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!("No data found for pair {}", params.pair_id),
-                }),
-            )
-        })?;
+        .map_err(pricing_error)?;
 
-    let signature = state.signing_service.sign_twap(twap_).map_err(|e| {
+    let signature = state.signing_service.sign_payload(&payload).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(ErrorResponse {
@@ -106,28 +256,302 @@ async fn get_twap(
             }),
         )
     })?;
+    let message_hash = state.signing_service.message_hash(&payload).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Failed to hash TWAP: {}", e),
+            }),
+        )
+    })?;
+    let scheme = state.signing_service.scheme();
+    let public_key = state.signing_service.get_public_key().to_string();
 
     let p2p_message = TwapMessage {
-        pair_id: params.pair_id.clone(),
-        twap: BigInt::from((twap_) as u64).to_string(),
-        period,
+        payload: payload.clone(),
+        scheme,
         signature: signature.clone(),
-        timestamp: std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-        public_key: state.signing_service.get_public_key().to_string(),
+        public_key: public_key.clone(),
     };
 
     // Broadcast to P2P network
-    if let Err(e) = state.p2p_sender.send(p2p_message) {
+    if let Err(e) = state.p2p_sender.send(P2PCommand::Broadcast(p2p_message)) {
         eprintln!("Failed to broadcast TWAP update: {}", e);
     }
 
     Ok(Json(TwapResponse {
+        signed_message: payload.canonical_message(),
+        derived: payload.is_derived(),
+        payload,
+        message_hash,
+        scheme,
+        signature,
+        public_key,
+        metadata,
+    }))
+}
+
+async fn get_signed_twap(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<TwapQuery>,
+) -> Result<Json<SignedTwapResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let period = params.period.unwrap_or(3600);
+    let aggregation = params.aggregation.unwrap_or_default();
+    let end = window_end(params.end_time, params.end_block).map_err(pricing_error)?;
+    let PricedWindow { payload, metadata } = state
+        .pricing
+        .price(&params.pair_id, period, aggregation, end)
+        .await
+        .map_err(pricing_error)?;
+
+    let signatures = state
+        .consensus
+        .gather_signatures(&payload, &state.p2p_sender)
+        .await
+        .map_err(pricing_error)?;
+
+    Ok(Json(SignedTwapResponse {
+        signed_message: payload.canonical_message(),
+        derived: payload.is_derived(),
+        payload,
+        signatures,
+        metadata,
+    }))
+}
+
+async fn get_candles(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<CandlesQuery>,
+) -> Result<Json<CandlesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let resolution = params.resolution.seconds();
+    let end_time = params.end_time.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    });
+    let start_time = params
+        .start_time
+        .unwrap_or_else(|| end_time.saturating_sub(resolution * DEFAULT_CANDLES));
+    // Align on the resolution so the first candle covers its whole bucket
+    let start_time = start_time - start_time % resolution;
+
+    if start_time > end_time {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "start_time must not be after end_time".to_string(),
+        ));
+    }
+    if (end_time - start_time) / resolution >= MAX_CANDLES {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Range exceeds {} candles, use a coarser resolution",
+                MAX_CANDLES
+            ),
+        ));
+    }
+
+    let read_error = |e: anyhow::Error| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read entries: {}", e),
+        )
+    };
+    let build_error = |e: anyhow::Error| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to build candles: {}", e),
+        )
+    };
+
+    // Older data may only survive as candles rolled up by the retention task, so
+    // combine those with minute candles built from the remaining raw entries
+    let mut candles = Vec::new();
+    if resolution >= Resolution::OneHour.seconds() {
+        candles.extend(
+            state
+                .redis_client
+                .get_stored_candles(&params.pair_id, Resolution::OneHour, start_time, end_time)
+                .await
+                .map_err(read_error)?,
+        );
+    }
+    candles.extend(
+        state
+            .redis_client
+            .get_stored_candles(&params.pair_id, Resolution::OneMinute, start_time, end_time)
+            .await
+            .map_err(read_error)?,
+    );
+    let entries = state
+        .redis_client
+        .get_spot_entries(&params.pair_id, Some(start_time), Some(end_time))
+        .await
+        .map_err(read_error)?;
+    candles.extend(
+        aggregation::build_candles(&entries, Resolution::OneMinute.seconds())
+            .map_err(build_error)?,
+    );
+    candles.sort_by_key(|candle| candle.start);
+    let candles = aggregation::downsample_candles(&candles, resolution).map_err(build_error)?;
+
+    Ok(Json(CandlesResponse {
+        network: state.config.network.name().to_string(),
+        decimals: state.config.decimals_for(&params.pair_id),
+        pair_id: params.pair_id,
+        resolution: params.resolution,
+        start_time,
+        end_time,
+        candles,
+    }))
+}
+
+async fn get_entries(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<EntriesQuery>,
+) -> Result<Json<EntriesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let limit = params.limit.unwrap_or(DEFAULT_ENTRIES_LIMIT);
+    if limit == 0 || limit > MAX_ENTRIES_LIMIT {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_ENTRIES_LIMIT),
+        ));
+    }
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(str::parse::<EntryCursor>)
+        .transpose()
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let filter = SpotEntryFilter {
+        start_time: params.start_time,
+        end_time: params.end_time,
+        start_block: params.start_block,
+        end_block: params.end_block,
+        source: params.source,
+        publisher: params.publisher,
+    };
+    let (entries, next_cursor) = state
+        .redis_client
+        .query_spot_entries(&params.pair_id, &filter, cursor, limit, MAX_ENTRIES_SCANNED)
+        .await
+        .map_err(|e| {
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read entries: {}", e),
+            )
+        })?;
+
+    Ok(Json(EntriesResponse {
+        network: state.config.network.name().to_string(),
+        pair_id: params.pair_id,
+        entries,
+        next_cursor: next_cursor.map(|cursor| cursor.to_string()),
+    }))
+}
+
+async fn get_pairs(
+    State(state): State<Arc<ApiState>>,
+) -> Result<Json<PairsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let pairs = state.redis_client.get_pairs().await.map_err(|e| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read pairs: {}", e),
+        )
+    })?;
+
+    Ok(Json(PairsResponse {
+        network: state.config.network.name().to_string(),
+        pairs: pairs
+            .into_iter()
+            .map(|info| PairSummary {
+                decimals: state.config.decimals_for(&info.pair_id),
+                info,
+            })
+            .collect(),
+    }))
+}
+
+async fn get_breakdown(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<BreakdownQuery>,
+) -> Result<Json<BreakdownResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let period = params.period.unwrap_or(3600);
+    let aggregation = params.aggregation.unwrap_or_default();
+    let group_by = params.group_by.unwrap_or_default();
+    let end = window_end(params.end_time, params.end_block).map_err(pricing_error)?;
+
+    let Breakdown {
+        window_start,
+        window_end,
+        end_block,
+        aggregate,
+        contributions,
+    } = state
+        .pricing
+        .breakdown(&params.pair_id, period, aggregation, group_by, end)
+        .await
+        .map_err(pricing_error)?;
+
+    Ok(Json(BreakdownResponse {
+        network: state.config.network.name().to_string(),
+        decimals: state.config.decimals_for(&params.pair_id),
         pair_id: params.pair_id,
-        twap: BigInt::from((twap_) as u64).to_string(),
         period,
-        signature,
+        window_start,
+        window_end,
+        end_block,
+        aggregation,
+        group_by,
+        aggregate,
+        contributions,
+    }))
+}
+
+async fn get_consensus(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<ConsensusQuery>,
+) -> Result<Json<ConsensusResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let period = params.period.unwrap_or(3600);
+    let aggregation = params.aggregation.unwrap_or_default();
+    let end = window_end(params.end_time, params.end_block).map_err(pricing_error)?;
+
+    let consensus = state
+        .consensus
+        .check(&params.pair_id, period, aggregation, end)
+        .await
+        .map_err(pricing_error)?;
+
+    Ok(Json(ConsensusResponse {
+        network: state.config.network.name().to_string(),
+        consensus,
     }))
 }
+
+/// Where a window ends, from the mutually exclusive `end_time` and `end_block` parameters
+fn window_end(end_time: Option<u64>, end_block: Option<u64>) -> Result<WindowEnd, PricingError> {
+    match (end_time, end_block) {
+        (None, None) => Ok(WindowEnd::Now),
+        (Some(end_time), None) => Ok(WindowEnd::Timestamp(end_time)),
+        (None, Some(end_block)) => Ok(WindowEnd::Block(end_block)),
+        (Some(_), Some(_)) => Err(PricingError::InvalidRequest(
+            "end_time and end_block are mutually exclusive".to_string(),
+        )),
+    }
+}
+
+fn api_error(status: StatusCode, error: String) -> (StatusCode, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse { error }))
+}
+
+fn pricing_error(e: PricingError) -> (StatusCode, Json<ErrorResponse>) {
+    let status = match e {
+        PricingError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        PricingError::NotFound(_) => StatusCode::NOT_FOUND,
+        PricingError::Stale(_) | PricingError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        PricingError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    api_error(status, e.to_string())
+}
@@ -1,27 +1,171 @@
 // src/config/mod.rs
 
+use crate::services::aggregation::OutlierFilter;
+use crate::services::signing::SigningScheme;
 use anyhow::{Context, Result};
 use libp2p::Multiaddr;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub redis_url: String,
     pub apibara_api_key: String,
+    pub network: Network,
+    pub apibara_uri: String,
     pub contract_address: String,
+    pub publisher_registry_address: Option<String>,
+    pub event_sources: Vec<EventSource>,
     pub server_host: String,
     pub server_port: u16,
     pub starting_block: u64,
+    pub rescan_from_block: Option<u64>,
+    pub indexer_max_retries: u32,
+    pub indexer_max_lag: u64,
     pub private_key: String,
+    pub signing_scheme: SigningScheme,
+    pub max_observation_age: u64,
+    pub min_observations: usize,
+    pub outlier_filter: OutlierFilter,
+    pub derivation_quote_currency: String,
+    pub default_decimals: u32,
+    pub pair_decimals: HashMap<String, u32>,
     pub p2p: P2PConfig,
+    pub retention: RetentionConfig,
+    pub consensus: ConsensusConfig,
+}
+
+/// Starknet network the indexer streams from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Sepolia,
+    /// Any other network; its Apibara URI and contract addresses must be configured
+    Custom(String),
+}
+
+impl Network {
+    /// Name used in Redis keys and API responses
+    pub fn name(&self) -> &str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Sepolia => "sepolia",
+            Network::Custom(name) => name,
+        }
+    }
+
+    pub fn default_apibara_uri(&self) -> Option<&'static str> {
+        match self {
+            Network::Mainnet => Some("https://mainnet.starknet.a5a.ch"),
+            Network::Sepolia => Some("https://sepolia.starknet.a5a.ch"),
+            Network::Custom(_) => None,
+        }
+    }
+
+    /// Address of the Pragma oracle contract
+    pub fn default_oracle_address(&self) -> Option<&'static str> {
+        match self {
+            Network::Mainnet => {
+                Some("0x2a85bd616f912537c50a49a4076db02c00b29b2cdc8a197ce92ed1837fa875b")
+            }
+            Network::Sepolia => {
+                Some("0x36031daa264c24520b11d93af622c848b2499b66b41d611bac95e13cfca131a")
+            }
+            Network::Custom(_) => None,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" => Err(anyhow::anyhow!("Network name must not be empty")),
+            "mainnet" => Ok(Network::Mainnet),
+            "sepolia" => Ok(Network::Sepolia),
+            other => {
+                // The name ends up in Redis keys, keep it to a safe charset
+                if !other
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err(anyhow::anyhow!("Invalid network name: {}", other));
+                }
+                // It is signed as a Cairo short string in STARK payloads
+                if other.len() > 31 {
+                    return Err(anyhow::anyhow!(
+                        "Network name must be at most 31 characters: {}",
+                        other
+                    ));
+                }
+                Ok(Network::Custom(other.to_string()))
+            }
+        }
+    }
+}
+
+/// Kind of Pragma event the indexer can decode and store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    SpotEntry,
+    FutureEntry,
+    Checkpoint,
+    PublisherRegistry,
+}
+
+impl EventKind {
+    /// Event selectors (first event key) emitted for this kind
+    pub fn selectors(&self) -> &'static [&'static str] {
+        match self {
+            EventKind::SpotEntry => &[SUBMITTED_SPOT_ENTRY_SELECTOR],
+            EventKind::FutureEntry => &[SUBMITTED_FUTURE_ENTRY_SELECTOR],
+            EventKind::Checkpoint => &[CHECKPOINT_SPOT_ENTRY_SELECTOR],
+            EventKind::PublisherRegistry => &[
+                REGISTERED_PUBLISHER_SELECTOR,
+                UPDATED_PUBLISHER_ADDRESS_SELECTOR,
+                DELETED_PUBLISHER_SELECTOR,
+            ],
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "spot_entry" => Ok(EventKind::SpotEntry),
+            "future_entry" => Ok(EventKind::FutureEntry),
+            "checkpoint" => Ok(EventKind::Checkpoint),
+            "publisher_registry" => Ok(EventKind::PublisherRegistry),
+            other => Err(anyhow::anyhow!("Unknown event kind: {}", other)),
+        }
+    }
+}
+
+/// A contract and the kind of events to index from it
+#[derive(Debug, Clone)]
+pub struct EventSource {
+    pub kind: EventKind,
+    pub contract_address: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct P2PConfig {
     pub listen_address: Multiaddr,
     pub bootstrap_peers: Vec<Multiaddr>,
+    /// Public keys, lowercased, whose signed messages are accepted from peers,
+    /// including the consensus signers. Empty accepts any validly signed message.
+    pub trusted_signers: Vec<String>,
+    /// File holding the node's libp2p identity, created on first start. Without
+    /// it the node gets a new peer ID on every start.
+    pub key_file: Option<PathBuf>,
+    /// Seconds a gossiped message's signing time may differ from the local clock
+    pub max_clock_skew: u64,
 }
 
 impl P2PConfig {
@@ -39,27 +183,215 @@ impl P2PConfig {
             .collect::<std::result::Result<Vec<_>, _>>()
             .context("Invalid bootstrap peer address")?;
 
+        // Keys listed inline and in a file, one per line, are both trusted
+        let mut trusted_signers: Vec<String> = env::var("P2P_TRUSTED_SIGNERS")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .map(|key| key.trim().to_lowercase())
+            .filter(|key| !key.is_empty())
+            .collect();
+        if let Ok(path) = env::var("P2P_TRUSTED_SIGNERS_FILE") {
+            if !path.is_empty() {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read trusted signers from {}", path))?;
+                trusted_signers.extend(
+                    contents
+                        .lines()
+                        .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+                        .filter(|key| !key.is_empty()),
+                );
+            }
+        }
+
+        let key_file = env::var("P2P_KEY_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
         Ok(P2PConfig {
             listen_address,
             bootstrap_peers,
+            trusted_signers,
+            key_file,
+            max_clock_skew: env::var("P2P_MAX_CLOCK_SKEW")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("P2P_MAX_CLOCK_SKEW must be a valid number")?,
+        })
+    }
+}
+
+/// How long data is kept at each resolution; `None` keeps it forever
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Raw spot entries older than this many days are rolled into one-minute candles
+    pub raw_days: Option<u64>,
+    /// One-minute candles older than this many days are rolled into hourly candles
+    pub minute_candle_days: Option<u64>,
+    /// Seconds between two retention passes
+    pub interval: u64,
+}
+
+impl RetentionConfig {
+    pub fn new() -> Result<Self> {
+        let raw_days = parse_optional_days("RETENTION_RAW_DAYS")?;
+        let minute_candle_days = parse_optional_days("RETENTION_MINUTE_CANDLE_DAYS")?;
+        if let (Some(raw_days), Some(minute_candle_days)) = (raw_days, minute_candle_days) {
+            anyhow::ensure!(
+                minute_candle_days >= raw_days,
+                "RETENTION_MINUTE_CANDLE_DAYS must not be lower than RETENTION_RAW_DAYS"
+            );
+        }
+
+        let interval = env::var("RETENTION_INTERVAL")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse()
+            .context("RETENTION_INTERVAL must be a valid number")?;
+        anyhow::ensure!(interval > 0, "RETENTION_INTERVAL must be positive");
+
+        Ok(RetentionConfig {
+            raw_days,
+            minute_candle_days,
+            interval,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.raw_days.is_some() || self.minute_candle_days.is_some()
+    }
+}
+
+/// How attestations gossiped by other nodes are compared with the local value
+#[derive(Debug, Clone)]
+pub struct ConsensusConfig {
+    /// Public keys of the nodes whose attestations count towards the quorum, the
+    /// local node always counts. Defaults to the trusted P2P signers; without
+    /// either no quorum is ever reached, since anyone can generate keys.
+    pub signers: Vec<String>,
+    /// Agreeing signers required, a majority of the signers when unset
+    pub threshold: Option<usize>,
+    /// Largest deviation from the consensus value, in basis points, that still agrees
+    pub tolerance_bps: u64,
+    /// Largest gap in seconds between a peer's window end and the local one
+    pub window_tolerance: u64,
+    /// How long to wait for peer signatures on a multi-signed TWAP, in milliseconds
+    pub signature_timeout: u64,
+}
+
+impl ConsensusConfig {
+    pub fn new() -> Result<Self> {
+        let signers = env::var("CONSENSUS_SIGNERS")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .map(|key| key.trim().to_lowercase())
+            .filter(|key| !key.is_empty())
+            .collect();
+
+        let threshold = env::var("CONSENSUS_THRESHOLD")
+            .ok()
+            .filter(|threshold| !threshold.is_empty())
+            .map(|threshold| threshold.parse())
+            .transpose()
+            .context("CONSENSUS_THRESHOLD must be a valid number")?;
+        anyhow::ensure!(
+            threshold != Some(0),
+            "CONSENSUS_THRESHOLD must be at least 1"
+        );
+
+        Ok(ConsensusConfig {
+            signers,
+            threshold,
+            tolerance_bps: env::var("CONSENSUS_TOLERANCE_BPS")
+                .unwrap_or_else(|_| "50".to_string())
+                .parse()
+                .context("CONSENSUS_TOLERANCE_BPS must be a valid number")?,
+            window_tolerance: env::var("CONSENSUS_WINDOW_TOLERANCE")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("CONSENSUS_WINDOW_TOLERANCE must be a valid number")?,
+            signature_timeout: env::var("CONSENSUS_SIGNATURE_TIMEOUT_MS")
+                .unwrap_or_else(|_| "2000".to_string())
+                .parse()
+                .context("CONSENSUS_SIGNATURE_TIMEOUT_MS must be a valid number")?,
         })
     }
 }
 
+/// Reads a number of days from `var`; unset or empty means no limit
+fn parse_optional_days(var: &str) -> Result<Option<u64>> {
+    let days = env::var(var)
+        .ok()
+        .filter(|days| !days.is_empty())
+        .map(|days| days.parse::<u64>())
+        .transpose()
+        .with_context(|| format!("{} must be a valid number", var))?;
+    anyhow::ensure!(days != Some(0), "{} must be at least 1 day", var);
+    Ok(days)
+}
+
 impl Config {
     pub fn new() -> Result<Self> {
         dotenv::dotenv().ok();
-        let p2p = P2PConfig::new()?;
+        let mut p2p = P2PConfig::new()?;
+        let retention = RetentionConfig::new()?;
+        let mut consensus = ConsensusConfig::new()?;
+
+        // One set of trusted signers: the quorum defaults to every trusted signer,
+        // and gossip from the quorum's signers is always accepted
+        if consensus.signers.is_empty() {
+            consensus.signers = p2p.trusted_signers.clone();
+        }
+        p2p.trusted_signers
+            .extend(consensus.signers.iter().cloned());
+        p2p.trusted_signers.sort();
+        p2p.trusted_signers.dedup();
+        anyhow::ensure!(
+            consensus.threshold.is_none() || !consensus.signers.is_empty(),
+            "CONSENSUS_THRESHOLD requires CONSENSUS_SIGNERS or P2P_TRUSTED_SIGNERS"
+        );
+
+        let network: Network = env::var("NETWORK")
+            .unwrap_or_else(|_| "sepolia".to_string())
+            .parse()
+            .context("Invalid NETWORK")?;
+
+        let apibara_uri = match env::var("APIBARA_URI") {
+            Ok(uri) => uri,
+            Err(_) => network
+                .default_apibara_uri()
+                .context("APIBARA_URI must be set for a custom network")?
+                .to_string(),
+        };
+
+        let contract_address = match env::var("CONTRACT_ADDRESS") {
+            Ok(address) => address,
+            Err(_) => {
+                println!("Using default contract address for {}", network.name());
+                network
+                    .default_oracle_address()
+                    .context("CONTRACT_ADDRESS must be set for a custom network")?
+                    .to_string()
+            }
+        };
+        let publisher_registry_address = env::var("PUBLISHER_REGISTRY_ADDRESS").ok();
+        let event_sources = parse_event_sources(
+            &env::var("INDEXED_EVENTS").unwrap_or_else(|_| "spot_entry".to_string()),
+            &contract_address,
+            publisher_registry_address.as_deref(),
+        )?;
+
         Ok(Config {
             redis_url: env::var("REDIS_URL")
                 .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
 
             apibara_api_key: env::var("APIBARA_API_KEY").context("APIBARA_API_KEY must be set")?,
 
-            contract_address: env::var("CONTRACT_ADDRESS").unwrap_or_else(|_| {
-                println!("Using default contract address");
-                "0x36031daa264c24520b11d93af622c848b2499b66b41d611bac95e13cfca131a".to_string()
-            }),
+            network,
+            apibara_uri,
+
+            contract_address,
+            publisher_registry_address,
+            event_sources,
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
 
             server_port: env::var("SERVER_PORT")
@@ -72,16 +404,207 @@ impl Config {
                 .parse()
                 .context("STARTING_BLOCK must be a valid number")?,
 
+            rescan_from_block: env::var("RESCAN_FROM_BLOCK")
+                .ok()
+                .filter(|block| !block.is_empty())
+                .map(|block| block.parse())
+                .transpose()
+                .context("RESCAN_FROM_BLOCK must be a valid number")?,
+
+            indexer_max_retries: env::var("INDEXER_MAX_RETRIES")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .context("INDEXER_MAX_RETRIES must be a valid number")?,
+
+            indexer_max_lag: env::var("INDEXER_MAX_LAG")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .context("INDEXER_MAX_LAG must be a valid number")?,
+
             private_key: env::var("PRIVATE_KEY")
                 .context("PRIVATE_KEY environment variable must be set")?,
+
+            signing_scheme: env::var("SIGNING_SCHEME")
+                .unwrap_or_else(|_| "secp256k1".to_string())
+                .parse()
+                .context("SIGNING_SCHEME must be secp256k1 or stark")?,
+
+            max_observation_age: env::var("MAX_OBSERVATION_AGE")
+                .unwrap_or_else(|_| "600".to_string())
+                .parse()
+                .context("MAX_OBSERVATION_AGE must be a valid number")?,
+
+            min_observations: env::var("MIN_OBSERVATIONS")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .context("MIN_OBSERVATIONS must be a valid number")?,
+
+            outlier_filter: parse_outlier_filter(
+                &env::var("OUTLIER_FILTER").unwrap_or_else(|_| "none".to_string()),
+                &env::var("OUTLIER_MAD_THRESHOLD").unwrap_or_else(|_| "3".to_string()),
+                &env::var("OUTLIER_BAND_PERCENT").unwrap_or_else(|_| "5".to_string()),
+            )?,
+
+            derivation_quote_currency: env::var("DERIVATION_QUOTE_CURRENCY")
+                .unwrap_or_else(|_| "USD".to_string()),
+
+            default_decimals: env::var("DEFAULT_DECIMALS")
+                .unwrap_or_else(|_| DEFAULT_PAIR_DECIMALS.to_string())
+                .parse()
+                .context("DEFAULT_DECIMALS must be a valid number")?,
+
+            pair_decimals: parse_pair_decimals(
+                &env::var("PAIR_DECIMALS").unwrap_or_else(|_| "".to_string()),
+            )?,
             p2p,
+            retention,
+            consensus,
+        })
+    }
+
+    /// Number of decimals the fixed-point prices of `pair_id` are scaled by
+    pub fn decimals_for(&self, pair_id: &str) -> u32 {
+        self.pair_decimals
+            .get(pair_id)
+            .copied()
+            .unwrap_or(self.default_decimals)
+    }
+}
+
+/// Parses `kind` or `kind@contract_address` items separated by commas, e.g.
+/// `spot_entry,checkpoint,publisher_registry@0x...`. Without an explicit address,
+/// oracle events come from `oracle_address` and registry events from `registry_address`.
+fn parse_event_sources(
+    value: &str,
+    oracle_address: &str,
+    registry_address: Option<&str>,
+) -> Result<Vec<EventSource>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|item| {
+            let (kind, address) = match item.split_once('@') {
+                Some((kind, address)) => (kind, Some(address.to_string())),
+                None => (item, None),
+            };
+            let kind: EventKind = kind.parse()?;
+            let contract_address = match (address, kind) {
+                (Some(address), _) => address,
+                (None, EventKind::PublisherRegistry) => registry_address
+                    .context("PUBLISHER_REGISTRY_ADDRESS must be set to index publisher_registry")?
+                    .to_string(),
+                (None, _) => oracle_address.to_string(),
+            };
+            Ok(EventSource {
+                kind,
+                contract_address,
+            })
+        })
+        .collect()
+}
+
+/// Parses `PAIR:DECIMALS` pairs separated by commas, e.g. `BTC/USD:8,ETH/STRK:18`
+fn parse_pair_decimals(value: &str) -> Result<HashMap<String, u32>> {
+    value
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|item| {
+            let (pair_id, decimals) = item
+                .rsplit_once(':')
+                .with_context(|| format!("Invalid PAIR_DECIMALS entry: {}", item))?;
+            let decimals = decimals
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid decimals for pair {}", pair_id))?;
+            Ok((pair_id.trim().to_string(), decimals))
         })
+        .collect()
+}
+
+/// Builds the outlier filter named by `method` (`none`, `mad` or `band`) with its threshold
+fn parse_outlier_filter(
+    method: &str,
+    mad_threshold: &str,
+    band_percent: &str,
+) -> Result<OutlierFilter> {
+    let parse_threshold = |value: &str, var: &str| -> Result<f64> {
+        let threshold: f64 = value
+            .trim()
+            .parse()
+            .with_context(|| format!("{} must be a valid number", var))?;
+        anyhow::ensure!(
+            threshold.is_finite() && threshold > 0.0,
+            "{} must be positive",
+            var
+        );
+        Ok(threshold)
+    };
+
+    match method.trim().to_lowercase().as_str() {
+        "none" | "" => Ok(OutlierFilter::None),
+        "mad" => Ok(OutlierFilter::Mad {
+            threshold: parse_threshold(mad_threshold, "OUTLIER_MAD_THRESHOLD")?,
+        }),
+        "band" => Ok(OutlierFilter::Band {
+            percent: parse_threshold(band_percent, "OUTLIER_BAND_PERCENT")?,
+        }),
+        other => anyhow::bail!("OUTLIER_FILTER must be none, mad or band, got {}", other),
     }
 }
 
 // Constants for event selectors
 pub const SUBMITTED_SPOT_ENTRY_SELECTOR: &str =
     "0x280bb2099800026f90c334a3a23888ffe718a2920ffbbf4f44c6d3d5efb613c";
+pub const SUBMITTED_FUTURE_ENTRY_SELECTOR: &str =
+    "0x3cba4bf3c7927a18934ab2c6b812a768d586eb481cef0b0ff1534b85613dadc";
+pub const CHECKPOINT_SPOT_ENTRY_SELECTOR: &str =
+    "0x1feaa3d5cdbfc5df79bd2ade44c1ca611c1f647a372ee0c023a820597ff4927";
+pub const REGISTERED_PUBLISHER_SELECTOR: &str =
+    "0x250e468bf3a3146d8e71a57f6f67196cc210dc89aa27041141f558d24658865";
+pub const UPDATED_PUBLISHER_ADDRESS_SELECTOR: &str =
+    "0x1cd0d3cf92741796b2f19cfadae5b498138883087b687ee30814d9c00184d39";
+pub const DELETED_PUBLISHER_SELECTOR: &str =
+    "0x165bad2f486fc66e699752f0e23da4fe64e9a2f96a756411ce30cfa583b9880";
+
+// Pragma publishes most pairs with 8 decimals
+pub const DEFAULT_PAIR_DECIMALS: u32 = 8;
+
+// Candles returned by /api/candles when no start_time is given, and the most allowed
+pub const DEFAULT_CANDLES: u64 = 100;
+pub const MAX_CANDLES: u64 = 1000;
+
+// Raw entries endpoint page sizes, and how many entries one request may scan
+pub const DEFAULT_ENTRIES_LIMIT: usize = 100;
+pub const MAX_ENTRIES_LIMIT: usize = 1000;
+pub const MAX_ENTRIES_SCANNED: usize = 10_000;
 
 // Redis key prefixes
 pub const REDIS_KEY_PREFIX_SPOT: &str = "spot:";
+pub const REDIS_KEY_PREFIX_SPOT_IDS: &str = "spot_ids:";
+pub const REDIS_KEY_PREFIX_FUTURE: &str = "future:";
+pub const REDIS_KEY_PREFIX_CHECKPOINT: &str = "checkpoint:";
+pub const REDIS_KEY_PUBLISHER_REGISTRY: &str = "publisher_registry";
+pub const REDIS_KEY_BLOCK_INDEX: &str = "spot_index:blocks";
+pub const REDIS_KEY_INDEXER_CURSOR: &str = "indexer:cursor";
+pub const REDIS_KEY_BLOCK_TIMESTAMPS: &str = "blocks";
+pub const REDIS_KEY_PAIRS: &str = "pairs";
+pub const REDIS_KEY_PREFIX_PAIR_SOURCES: &str = "pair_source_counts:";
+pub const REDIS_KEY_PREFIX_PAIR_PUBLISHERS: &str = "pair_publisher_counts:";
+pub const REDIS_KEY_PREFIX_CANDLES: &str = "candles:";
+pub const REDIS_KEY_PREFIX_ATTESTATIONS: &str = "attestations:";
+
+// Sets of the sources and publishers of each pair, replaced by the counts above
+pub const REDIS_KEY_PREFIX_LEGACY_PAIR_SOURCES: &str = "pair_sources:";
+pub const REDIS_KEY_PREFIX_LEGACY_PAIR_PUBLISHERS: &str = "pair_publishers:";
+
+// Attestations received from peers are kept this many seconds past their window end
+pub const ATTESTATION_TTL: u64 = 86400;
+
+// Co-signature requests priced at once, and how many one signer may make per window
+pub const MAX_CONCURRENT_COSIGNS: usize = 4;
+pub const COSIGN_REQUESTS_PER_SIGNER: u32 = 30;
+pub const COSIGN_RATE_WINDOW: u64 = 60;
+
+// Blocks deeper than this below the stream head are assumed to never be reorged
+pub const MAX_REORG_DEPTH: u64 = 1000;
@@ -8,10 +8,14 @@ mod services;
 mod types;
 
 use config::Config;
-use services::p2p::{P2PService, TwapMessage};
+use services::consensus::ConsensusService;
+use services::indexer::IndexerState;
+use services::p2p::P2PService;
+use services::pricing::PricingService;
 use services::redis_client::RedisClient;
+use services::retention::RetentionService;
 use services::{Indexer, SigningService};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, RwLock};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -28,17 +32,34 @@ async fn main() -> Result<()> {
         }
     };
 
-    let redis_client = RedisClient::new(&config.redis_url)?;
+    let redis_client = RedisClient::new(&config.redis_url, config.network.name())?;
+    redis_client.migrate().await?;
     let api_redis_client = redis_client.clone();
-    let signing_service = SigningService::new(&config.private_key)?;
+    let retention_redis_client = redis_client.clone();
+    let p2p_redis_client = redis_client.clone();
+    let signing_service = SigningService::new(&config.private_key, config.signing_scheme)?;
 
     let indexer_config = config.clone();
+    let api_config = config.clone();
+
+    // Shared so the API can report indexer health and lag
+    let indexer_state = Arc::new(RwLock::new(IndexerState::default()));
+    let api_indexer_state = indexer_state.clone();
+
+    // Shared by the API and the P2P service, which co-signs peers' prices
+    let pricing = PricingService::new(config.clone(), redis_client.clone(), indexer_state.clone());
+    let consensus = ConsensusService::new(
+        config.consensus.clone(),
+        redis_client.clone(),
+        pricing.clone(),
+        signing_service.clone(),
+    );
 
     // Start the indexer in a separate task
     let indexer_handle = tokio::spawn(async move {
         println!("Starting indexer service...");
 
-        let indexer = Indexer::new(indexer_config.clone(), redis_client);
+        let indexer = Indexer::new(indexer_config.clone(), redis_client, indexer_state);
 
         println!("Indexer created, starting to run...");
 
@@ -47,13 +68,26 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Downsample old data in the background, off the ingestion path
+    if config.retention.is_enabled() {
+        let retention = RetentionService::new(config.retention.clone(), retention_redis_client);
+        tokio::spawn(async move {
+            println!("Starting retention service...");
+            if let Err(e) = retention.run().await {
+                eprintln!("Retention service error: {}", e);
+            }
+        });
+    }
+
     // Create channel for P2P message broadcasting
     let (p2p_sender, p2p_receiver) = mpsc::unbounded_channel();
 
     // Initialize P2P service
     let p2p_service = P2PService::new(
-        config.p2p.listen_address, // Remove .clone()
-        config.p2p.bootstrap_peers,
+        config.p2p,
+        config.network.name(),
+        p2p_redis_client,
+        consensus.clone(),
     )
     .await?;
 
@@ -70,7 +104,15 @@ async fn main() -> Result<()> {
     });
 
     // Start the API server
-    let app = api::create_router(api_redis_client, signing_service, p2p_sender);
+    let app = api::create_router(
+        api_config,
+        api_redis_client,
+        api_indexer_state,
+        pricing,
+        consensus,
+        signing_service,
+        p2p_sender,
+    );
 
     let addr = SocketAddr::new(config.server_host.parse()?, config.server_port);
     println!("API server starting on {}", addr);
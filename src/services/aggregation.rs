@@ -0,0 +1,713 @@
+use crate::types::candle::Candle;
+use crate::types::spot_entry::SpotEntry;
+use anyhow::{Context, Result};
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// How the spot entries of a window are reduced to a single price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregationMode {
+    /// Time-weighted average price
+    #[default]
+    Twap,
+    /// Median of every entry in the window
+    Median,
+    /// Volume-weighted average price
+    Vwap,
+    /// Median per source, then median across sources
+    MedianOfMedians,
+}
+
+impl AggregationMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregationMode::Twap => "twap",
+            AggregationMode::Median => "median",
+            AggregationMode::Vwap => "vwap",
+            AggregationMode::MedianOfMedians => "median_of_medians",
+        }
+    }
+}
+
+impl fmt::Display for AggregationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Aggregated price of a window along with what it was computed from
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub value: BigInt,
+    pub stats: WindowStats,
+}
+
+/// How much data backs an aggregate, used to judge its freshness
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowStats {
+    /// Entries inside the window
+    pub observations: usize,
+    pub oldest_observation: Option<u64>,
+    /// Latest entry the value depends on; the carried-in entry if the window is empty
+    pub newest_observation: Option<u64>,
+    /// Distinct sources inside the window
+    pub sources: usize,
+    /// Entries inside the window rejected as outliers, not counted in `observations`
+    pub rejected: usize,
+}
+
+impl WindowStats {
+    pub fn from_entries(
+        entries: &[SpotEntry],
+        carried_in: Option<&SpotEntry>,
+        rejected: usize,
+    ) -> Result<Self> {
+        let newest = match entries.last().or(carried_in) {
+            Some(entry) => Some(entry.timestamp_value()?),
+            None => None,
+        };
+        let oldest = match entries.first() {
+            Some(entry) => Some(entry.timestamp_value()?),
+            None => None,
+        };
+
+        Ok(WindowStats {
+            observations: entries.len(),
+            oldest_observation: oldest,
+            newest_observation: newest,
+            sources: entries
+                .iter()
+                .map(|entry| entry.source.as_str())
+                .collect::<HashSet<_>>()
+                .len(),
+            rejected,
+        })
+    }
+
+    /// Stats of a value computed from two windows: observations and rejections
+    /// add up, and the staler window bounds the newest observation
+    pub fn combine(a: &WindowStats, b: &WindowStats) -> WindowStats {
+        WindowStats {
+            observations: a.observations + b.observations,
+            oldest_observation: match (a.oldest_observation, b.oldest_observation) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            newest_observation: a.newest_observation.min(b.newest_observation),
+            sources: a.sources.max(b.sources),
+            rejected: a.rejected + b.rejected,
+        }
+    }
+}
+
+/// How entries far from the median of their window are rejected before aggregation
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutlierFilter {
+    #[default]
+    None,
+    /// Rejects prices more than `threshold` median absolute deviations from the median
+    Mad { threshold: f64 },
+    /// Rejects prices more than `percent` percent away from the median
+    Band { percent: f64 },
+}
+
+/// Window entries left after outlier rejection
+#[derive(Debug, Clone)]
+pub struct FilteredWindow {
+    pub entries: Vec<SpotEntry>,
+    /// Dropped if it is itself an outlier relative to the window
+    pub carried_in: Option<SpotEntry>,
+    pub rejected: Vec<SpotEntry>,
+}
+
+// Thresholds are applied as fixed-point integers with this precision
+const THRESHOLD_SCALE: u64 = 1_000_000;
+
+fn abs_diff(a: &BigInt, b: &BigInt) -> BigInt {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+fn scaled_threshold(threshold: f64) -> BigInt {
+    BigInt::from((threshold * THRESHOLD_SCALE as f64).round() as u64)
+}
+
+impl OutlierFilter {
+    /// Rejects the outliers among `entries`, judged against the median of the
+    /// window. `entries` must be sorted by timestamp and stay sorted.
+    pub fn apply(
+        &self,
+        entries: Vec<SpotEntry>,
+        carried_in: Option<SpotEntry>,
+    ) -> Result<FilteredWindow> {
+        let prices = entries
+            .iter()
+            .map(|entry| entry.price_value())
+            .collect::<Result<Vec<_>>>()?;
+        let Some(center) = median(prices.clone()) else {
+            return Ok(FilteredWindow {
+                entries,
+                carried_in,
+                rejected: Vec::new(),
+            });
+        };
+
+        // Outliers deviate from the median by more than `limit / scale`
+        let bound = match *self {
+            OutlierFilter::None => None,
+            OutlierFilter::Mad { threshold } => {
+                let deviations = prices
+                    .iter()
+                    .map(|price| abs_diff(price, &center))
+                    .collect();
+                // When at least half the prices equal the median the MAD is 0, and
+                // any price off the median is an outlier
+                let mad = median(deviations).unwrap_or_default();
+                Some((scaled_threshold(threshold) * mad, THRESHOLD_SCALE))
+            }
+            OutlierFilter::Band { percent } => {
+                Some((scaled_threshold(percent) * &center, 100 * THRESHOLD_SCALE))
+            }
+        };
+        let is_outlier = |price: &BigInt| match &bound {
+            Some((limit, scale)) => abs_diff(price, &center) * *scale > *limit,
+            None => false,
+        };
+
+        let mut kept = Vec::with_capacity(entries.len());
+        let mut rejected = Vec::new();
+        for (entry, price) in entries.into_iter().zip(&prices) {
+            if is_outlier(price) {
+                rejected.push(entry);
+            } else {
+                kept.push(entry);
+            }
+        }
+        let carried_in = match carried_in {
+            Some(entry) if is_outlier(&entry.price_value()?) => None,
+            carried_in => carried_in,
+        };
+
+        Ok(FilteredWindow {
+            entries: kept,
+            carried_in,
+            rejected,
+        })
+    }
+}
+
+/// Aggregates the entries of the window `[start, end]`. `entries` must be sorted
+/// by timestamp; `carried_in` is the last entry before `start` and is only used
+/// by the TWAP, where that price stays in force until the first update.
+/// `None` when the aggregate is undefined: without entries, or a VWAP without volume.
+pub fn aggregate(
+    mode: AggregationMode,
+    entries: &[SpotEntry],
+    carried_in: Option<&SpotEntry>,
+    start: u64,
+    end: u64,
+) -> Result<Option<BigInt>> {
+    match mode {
+        AggregationMode::Twap => {
+            let mut observations = Vec::with_capacity(entries.len() + 1);
+            if let Some(entry) = carried_in {
+                observations.push((start, entry.price_value()?));
+            }
+            for entry in entries {
+                observations.push((entry.timestamp_value()?, entry.price_value()?));
+            }
+            Ok(time_weighted_average(&observations, end))
+        }
+        AggregationMode::Median => {
+            let prices = entries
+                .iter()
+                .map(|entry| entry.price_value())
+                .collect::<Result<Vec<_>>>()?;
+            Ok(median(prices))
+        }
+        AggregationMode::Vwap => volume_weighted_average(entries),
+        AggregationMode::MedianOfMedians => {
+            let mut by_source: BTreeMap<&str, Vec<BigInt>> = BTreeMap::new();
+            for entry in entries {
+                by_source
+                    .entry(entry.source.as_str())
+                    .or_default()
+                    .push(entry.price_value()?);
+            }
+            Ok(median(by_source.into_values().filter_map(median).collect()))
+        }
+    }
+}
+
+/// Field entries are grouped by in a breakdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakdownKey {
+    #[default]
+    Source,
+    Publisher,
+}
+
+impl BreakdownKey {
+    fn of<'a>(&self, entry: &'a SpotEntry) -> &'a str {
+        match self {
+            BreakdownKey::Source => &entry.source,
+            BreakdownKey::Publisher => &entry.publisher,
+        }
+    }
+}
+
+/// What the entries of one source or publisher would aggregate to on their own
+#[derive(Debug, Clone, Serialize)]
+pub struct Contribution {
+    pub key: String,
+    pub observations: usize,
+    /// Observations rejected as outliers from the overall aggregate
+    pub rejected: usize,
+    /// Aggregate of all the group's entries, outliers included
+    pub value: Option<String>,
+    /// Deviation of `value` from the overall aggregate, in basis points
+    pub deviation_bps: Option<f64>,
+}
+
+/// Deviation of `value` from `reference` in basis points, to two decimals
+pub fn deviation_bps(value: &BigInt, reference: &BigInt) -> Option<f64> {
+    if *reference == BigInt::from(0) {
+        return None;
+    }
+    // Hundredths of a basis point, to keep two decimals
+    let scaled = (value - reference) * 1_000_000 / reference;
+    i64::try_from(&scaled)
+        .ok()
+        .map(|scaled| scaled as f64 / 100.0)
+}
+
+/// Aggregates the entries of the window `[start, end]` separately for each
+/// source or publisher, and compares each result with `overall`. Groups only
+/// see their own entries in the window, without a carried-in price. `rejected`
+/// are the entries left out of `overall` as outliers. Fails if any group
+/// fails to aggregate.
+pub fn breakdown(
+    mode: AggregationMode,
+    group_by: BreakdownKey,
+    entries: &[SpotEntry],
+    rejected: &[SpotEntry],
+    start: u64,
+    end: u64,
+    overall: &BigInt,
+) -> Result<Vec<Contribution>> {
+    let mut groups: BTreeMap<&str, Vec<&SpotEntry>> = BTreeMap::new();
+    for entry in entries {
+        groups.entry(group_by.of(entry)).or_default().push(entry);
+    }
+
+    let mut contributions = Vec::with_capacity(groups.len());
+    for (key, group) in groups {
+        let group: Vec<SpotEntry> = group.into_iter().cloned().collect();
+        // A group can be undefined on its own, e.g. a VWAP without any volume
+        let value = aggregate(mode, &group, None, start, end)
+            .with_context(|| format!("Failed to aggregate the entries of {}", key))?;
+        let deviation_bps = value
+            .as_ref()
+            .and_then(|value| deviation_bps(value, overall));
+
+        contributions.push(Contribution {
+            key: key.to_string(),
+            observations: group.len(),
+            rejected: rejected
+                .iter()
+                .filter(|entry| group_by.of(entry) == key)
+                .count(),
+            value: value.map(|value| value.to_string()),
+            deviation_bps,
+        });
+    }
+
+    Ok(contributions)
+}
+
+/// Weights each price by how long it stayed in force, i.e. until the next
+/// observation (or `end` for the last one). `observations` must be sorted by time.
+/// The result is rounded down to the price's own fixed-point precision.
+pub fn time_weighted_average(observations: &[(u64, BigInt)], end: u64) -> Option<BigInt> {
+    if observations.is_empty() {
+        return None;
+    }
+
+    let mut weighted_sum = BigInt::from(0);
+    let mut total_weight: u64 = 0;
+    for (i, (timestamp, price)) in observations.iter().enumerate() {
+        let until = observations
+            .get(i + 1)
+            .map(|(next, _)| *next)
+            .unwrap_or(end);
+        let weight = until.saturating_sub(*timestamp);
+        weighted_sum += price * weight;
+        total_weight += weight;
+    }
+
+    if total_weight == 0 {
+        // Every observation landed at the very end of the window
+        return observations.last().map(|(_, price)| price.clone());
+    }
+
+    Some(weighted_sum / total_weight)
+}
+
+/// Median, rounding down the mean of the two middle values for even counts
+pub fn median(mut values: Vec<BigInt>) -> Option<BigInt> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort();
+
+    let mid = values.len() / 2;
+    if values.len() % 2 == 1 {
+        Some(values.swap_remove(mid))
+    } else {
+        Some((&values[mid - 1] + &values[mid]) / 2)
+    }
+}
+
+fn volume_weighted_average(entries: &[SpotEntry]) -> Result<Option<BigInt>> {
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let mut weighted_sum = BigInt::from(0);
+    let mut total_volume = BigInt::from(0);
+    for entry in entries {
+        let volume = entry.volume_value()?;
+        weighted_sum += entry.price_value()? * &volume;
+        total_volume += volume;
+    }
+
+    // Undefined without volume, like an empty window
+    if total_volume == BigInt::from(0) {
+        return Ok(None);
+    }
+
+    Ok(Some(weighted_sum / total_volume))
+}
+
+/// Buckets `entries` into OHLCV candles of `resolution` seconds, aligned on
+/// multiples of the resolution. `entries` must be sorted by timestamp; buckets
+/// without entries are skipped.
+pub fn build_candles(entries: &[SpotEntry], resolution: u64) -> Result<Vec<Candle>> {
+    let mut candles = Vec::new();
+    let mut current: Option<CandleBuilder> = None;
+
+    for entry in entries {
+        let timestamp = entry.timestamp_value()?;
+        let start = timestamp - timestamp % resolution;
+        let price = entry.price_value()?;
+        let volume = entry.volume_value()?;
+
+        match current.as_mut() {
+            Some(builder) if builder.start == start => builder.add(price, volume),
+            _ => {
+                if let Some(builder) = current.take() {
+                    candles.push(builder.finish());
+                }
+                current = Some(CandleBuilder::new(start, price, volume));
+            }
+        }
+    }
+    candles.extend(current.map(CandleBuilder::finish));
+
+    Ok(candles)
+}
+
+/// Merges candles sorted by start into candles of `resolution`, which must be a
+/// multiple of their own width
+pub fn downsample_candles(candles: &[Candle], resolution: u64) -> Result<Vec<Candle>> {
+    let mut downsampled = Vec::new();
+    let mut current: Option<CandleBuilder> = None;
+
+    for candle in candles {
+        let start = candle.start - candle.start % resolution;
+
+        match current.as_mut() {
+            Some(builder) if builder.start == start => builder.merge(candle)?,
+            _ => {
+                if let Some(builder) = current.take() {
+                    downsampled.push(builder.finish());
+                }
+                current = Some(CandleBuilder::from_candle(start, candle)?);
+            }
+        }
+    }
+    downsampled.extend(current.map(CandleBuilder::finish));
+
+    Ok(downsampled)
+}
+
+fn parse_candle_value(value: &str) -> Result<BigInt> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid candle value '{}'", value))
+}
+
+struct CandleBuilder {
+    start: u64,
+    open: BigInt,
+    high: BigInt,
+    low: BigInt,
+    close: BigInt,
+    volume: BigInt,
+    observations: usize,
+}
+
+impl CandleBuilder {
+    fn new(start: u64, price: BigInt, volume: BigInt) -> Self {
+        CandleBuilder {
+            start,
+            open: price.clone(),
+            high: price.clone(),
+            low: price.clone(),
+            close: price,
+            volume,
+            observations: 1,
+        }
+    }
+
+    fn add(&mut self, price: BigInt, volume: BigInt) {
+        if price > self.high {
+            self.high = price.clone();
+        }
+        if price < self.low {
+            self.low = price.clone();
+        }
+        self.close = price;
+        self.volume += volume;
+        self.observations += 1;
+    }
+
+    fn from_candle(start: u64, candle: &Candle) -> Result<Self> {
+        Ok(CandleBuilder {
+            start,
+            open: parse_candle_value(&candle.open)?,
+            high: parse_candle_value(&candle.high)?,
+            low: parse_candle_value(&candle.low)?,
+            close: parse_candle_value(&candle.close)?,
+            volume: parse_candle_value(&candle.volume)?,
+            observations: candle.observations,
+        })
+    }
+
+    /// Extends the candle with a later one
+    fn merge(&mut self, candle: &Candle) -> Result<()> {
+        let high = parse_candle_value(&candle.high)?;
+        let low = parse_candle_value(&candle.low)?;
+        if high > self.high {
+            self.high = high;
+        }
+        if low < self.low {
+            self.low = low;
+        }
+        self.close = parse_candle_value(&candle.close)?;
+        self.volume += parse_candle_value(&candle.volume)?;
+        self.observations += candle.observations;
+        Ok(())
+    }
+
+    fn finish(self) -> Candle {
+        Candle {
+            start: self.start,
+            open: self.open.to_string(),
+            high: self.high.to_string(),
+            low: self.low.to_string(),
+            close: self.close.to_string(),
+            volume: self.volume.to_string(),
+            observations: self.observations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, price: u64) -> SpotEntry {
+        SpotEntry {
+            timestamp: timestamp.to_string(),
+            source: "SOURCE".to_string(),
+            publisher: "PUBLISHER".to_string(),
+            price: price.to_string(),
+            pair_id: "BTC/USD".to_string(),
+            volume: "0".to_string(),
+            block_number: 1,
+            transaction_hash: "0x1".to_string(),
+            event_index: timestamp,
+        }
+    }
+
+    fn entries(prices: &[u64]) -> Vec<SpotEntry> {
+        prices
+            .iter()
+            .enumerate()
+            .map(|(i, price)| entry(100 + i as u64, *price))
+            .collect()
+    }
+
+    fn prices(entries: &[SpotEntry]) -> Vec<BigInt> {
+        entries
+            .iter()
+            .map(|entry| entry.price_value().unwrap())
+            .collect()
+    }
+
+    fn big(values: &[u64]) -> Vec<BigInt> {
+        values.iter().map(|value| BigInt::from(*value)).collect()
+    }
+
+    #[test]
+    fn twap_weights_prices_by_time_in_force() {
+        let observations = vec![(100, BigInt::from(100)), (150, BigInt::from(200))];
+        assert_eq!(
+            time_weighted_average(&observations, 200),
+            Some(BigInt::from(150))
+        );
+        // Rounded down
+        let observations = vec![(0, BigInt::from(1)), (1, BigInt::from(2))];
+        assert_eq!(
+            time_weighted_average(&observations, 3),
+            Some(BigInt::from(1))
+        );
+        assert_eq!(time_weighted_average(&[], 200), None);
+    }
+
+    #[test]
+    fn twap_carries_in_the_last_price_before_the_window() {
+        let carried_in = entry(50, 100);
+        let value = aggregate(
+            AggregationMode::Twap,
+            &[entry(175, 200)],
+            Some(&carried_in),
+            100,
+            200,
+        )
+        .unwrap();
+        // 100 in force for 75s from the window start, then 200 for 25s
+        assert_eq!(value, Some(BigInt::from(125)));
+
+        let value = aggregate(AggregationMode::Twap, &[], Some(&carried_in), 100, 200).unwrap();
+        assert_eq!(value, Some(BigInt::from(100)));
+    }
+
+    #[test]
+    fn twap_observations_at_the_window_end() {
+        // An observation at the end carries no weight
+        let observations = vec![(100, BigInt::from(100)), (200, BigInt::from(300))];
+        assert_eq!(
+            time_weighted_average(&observations, 200),
+            Some(BigInt::from(100))
+        );
+        // Unless nothing else does
+        let observations = vec![(200, BigInt::from(300))];
+        assert_eq!(
+            time_weighted_average(&observations, 200),
+            Some(BigInt::from(300))
+        );
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(big(&[3, 1, 2])), Some(BigInt::from(2)));
+        assert_eq!(median(big(&[4, 1, 3, 2])), Some(BigInt::from(2)));
+        assert_eq!(median(Vec::new()), None);
+    }
+
+    #[test]
+    fn mad_filter_rejects_far_prices() {
+        let filter = OutlierFilter::Mad { threshold: 3.0 };
+        let window = filter
+            .apply(entries(&[100, 102, 98, 101, 99, 1000]), None)
+            .unwrap();
+        assert_eq!(prices(&window.entries), big(&[100, 102, 98, 101, 99]));
+        assert_eq!(prices(&window.rejected), big(&[1000]));
+    }
+
+    #[test]
+    fn mad_filter_with_zero_mad_rejects_any_price_off_the_median() {
+        let filter = OutlierFilter::Mad { threshold: 3.0 };
+        let window = filter
+            .apply(entries(&[100, 100, 100, 101, 1_000_000]), None)
+            .unwrap();
+        assert_eq!(prices(&window.entries), big(&[100, 100, 100]));
+        assert_eq!(prices(&window.rejected), big(&[101, 1_000_000]));
+
+        let window = filter.apply(entries(&[100, 100, 100]), None).unwrap();
+        assert_eq!(window.entries.len(), 3);
+        assert!(window.rejected.is_empty());
+    }
+
+    #[test]
+    fn band_filter_rejects_prices_outside_the_band() {
+        let filter = OutlierFilter::Band { percent: 5.0 };
+        // Median 102, so the band is 96.9 to 107.1
+        let window = filter
+            .apply(entries(&[100, 100, 104, 110]), Some(entry(50, 200)))
+            .unwrap();
+        assert_eq!(prices(&window.entries), big(&[100, 100, 104]));
+        assert_eq!(prices(&window.rejected), big(&[110]));
+        // The carried-in entry is judged against the same median
+        assert!(window.carried_in.is_none());
+
+        let window = filter
+            .apply(entries(&[100, 100]), Some(entry(50, 103)))
+            .unwrap();
+        assert!(window.carried_in.is_some());
+    }
+
+    #[test]
+    fn no_filter_keeps_every_entry() {
+        let window = OutlierFilter::None
+            .apply(entries(&[1, 1000, 1_000_000]), None)
+            .unwrap();
+        assert_eq!(window.entries.len(), 3);
+        assert!(window.rejected.is_empty());
+    }
+
+    #[test]
+    fn combined_stats_add_up_and_keep_the_staler_window() {
+        let a = WindowStats {
+            observations: 2,
+            oldest_observation: Some(10),
+            newest_observation: Some(50),
+            sources: 2,
+            rejected: 1,
+        };
+        let b = WindowStats {
+            observations: 3,
+            oldest_observation: Some(20),
+            newest_observation: Some(40),
+            sources: 1,
+            rejected: 2,
+        };
+        let combined = WindowStats::combine(&a, &b);
+        assert_eq!(combined.observations, 5);
+        assert_eq!(combined.oldest_observation, Some(10));
+        assert_eq!(combined.newest_observation, Some(40));
+        assert_eq!(combined.sources, 2);
+        assert_eq!(combined.rejected, 3);
+
+        // A window without any observation leaves nothing to vouch for freshness
+        let empty = WindowStats {
+            observations: 0,
+            oldest_observation: None,
+            newest_observation: None,
+            sources: 0,
+            rejected: 0,
+        };
+        let combined = WindowStats::combine(&a, &empty);
+        assert_eq!(combined.oldest_observation, Some(10));
+        assert_eq!(combined.newest_observation, None);
+    }
+}
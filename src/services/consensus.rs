@@ -0,0 +1,339 @@
+use crate::config::ConsensusConfig;
+use crate::services::aggregation::{self, AggregationMode};
+use crate::services::p2p::{P2PCommand, SignatureRequest, SignatureResponse, TwapMessage};
+use crate::services::pricing::{PricedWindow, PricingError, PricingService, WindowEnd};
+use crate::services::redis_client::RedisClient;
+use crate::services::signing::SigningScheme;
+use crate::services::SigningService;
+use crate::types::twap_payload::TwapPayload;
+use num_bigint::BigInt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// One signer's value for the window being checked
+#[derive(Debug, Clone, Serialize)]
+pub struct SignerValue {
+    pub public_key: String,
+    pub value: String,
+    pub window_end: u64,
+    /// Deviation from the consensus value, in basis points
+    pub deviation_bps: Option<f64>,
+    pub agrees: bool,
+    /// Whether the signer counts towards the quorum
+    pub counted: bool,
+    pub local: bool,
+}
+
+/// Agreement between the local value and the attestations of other nodes
+#[derive(Debug, Clone, Serialize)]
+pub struct Consensus {
+    pub pair_id: String,
+    pub period: u64,
+    pub aggregation: AggregationMode,
+    pub window_end: u64,
+    pub decimals: u32,
+    /// Median of the values of the counted signers
+    pub value: String,
+    pub local_value: String,
+    /// Counted signers within the tolerance of the consensus value
+    pub agreeing: usize,
+    pub signers: usize,
+    /// Unset without a configured signer set, in which case only the local
+    /// node counts and the quorum is never reached
+    pub threshold: Option<usize>,
+    pub authenticated: bool,
+    pub reached: bool,
+    pub tolerance_bps: u64,
+    pub attestations: Vec<SignerValue>,
+}
+
+/// A signature over a payload, from this node or a peer
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerSignature {
+    pub public_key: String,
+    pub scheme: SigningScheme,
+    pub signature: String,
+}
+
+/// Signatures gathered from the signers over the same payload
+#[derive(Debug, Clone, Serialize)]
+pub struct SignatureBundle {
+    pub signatures: Vec<PeerSignature>,
+    pub signers: usize,
+    pub threshold: usize,
+    pub reached: bool,
+}
+
+/// Compares locally computed prices with the attestations gossiped by other nodes
+#[derive(Clone)]
+pub struct ConsensusService {
+    config: ConsensusConfig,
+    redis_client: RedisClient,
+    pricing: PricingService,
+    signing_service: SigningService,
+    public_key: String,
+}
+
+impl ConsensusService {
+    pub fn new(
+        config: ConsensusConfig,
+        redis_client: RedisClient,
+        pricing: PricingService,
+        signing_service: SigningService,
+    ) -> Self {
+        ConsensusService {
+            config,
+            redis_client,
+            pricing,
+            public_key: signing_service.get_public_key().to_lowercase(),
+            signing_service,
+        }
+    }
+
+    /// Prices the window locally and checks how many signers attested to a value
+    /// within the tolerance of the median. A peer's attestation counts if its
+    /// window ends within `window_tolerance` seconds of the local one; the closest
+    /// one is used when a peer attested several times.
+    pub async fn check(
+        &self,
+        pair_id: &str,
+        period: u64,
+        aggregation: AggregationMode,
+        end: WindowEnd,
+    ) -> Result<Consensus, PricingError> {
+        let PricedWindow { payload, .. } = self
+            .pricing
+            .price(pair_id, period, aggregation, end)
+            .await?;
+        let local_value: BigInt = payload
+            .twap
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid local value {}: {}", payload.twap, e))?;
+        let window_end = payload.window_end;
+
+        let attestations = self
+            .redis_client
+            .get_attestations(
+                pair_id,
+                period,
+                aggregation,
+                window_end.saturating_sub(self.config.window_tolerance),
+                window_end + self.config.window_tolerance,
+            )
+            .await?;
+
+        let gap = |message: &TwapMessage| message.payload.window_end.abs_diff(window_end);
+        let mut closest: BTreeMap<String, TwapMessage> = BTreeMap::new();
+        for attestation in attestations {
+            // Only values of the same quantity are comparable
+            if attestation.payload.network != payload.network
+                || attestation.payload.decimals != payload.decimals
+                || attestation.payload.constituents != payload.constituents
+            {
+                continue;
+            }
+            let public_key = attestation.public_key.to_lowercase();
+            if public_key == self.public_key {
+                continue;
+            }
+            match closest.get(&public_key) {
+                Some(existing) if gap(existing) <= gap(&attestation) => {}
+                _ => {
+                    closest.insert(public_key, attestation);
+                }
+            }
+        }
+
+        let mut values = vec![(self.public_key.clone(), local_value.clone(), window_end)];
+        for (public_key, attestation) in closest {
+            if let Ok(value) = attestation.payload.twap.parse::<BigInt>() {
+                values.push((public_key, value, attestation.payload.window_end));
+            }
+        }
+
+        let known: HashSet<&str> = self.config.signers.iter().map(String::as_str).collect();
+        let is_counted =
+            |public_key: &str| known.contains(public_key) || public_key == self.public_key;
+        let value = aggregation::median(
+            values
+                .iter()
+                .filter(|(public_key, _, _)| is_counted(public_key))
+                .map(|(_, value, _)| value.clone())
+                .collect(),
+        )
+        .unwrap_or_else(|| local_value.clone());
+
+        let attestations: Vec<SignerValue> = values
+            .into_iter()
+            .map(|(public_key, signer_value, signer_window_end)| {
+                let deviation_bps = aggregation::deviation_bps(&signer_value, &value);
+                let agrees = match deviation_bps {
+                    Some(deviation) => deviation.abs() <= self.config.tolerance_bps as f64,
+                    None => signer_value == value,
+                };
+                SignerValue {
+                    agrees,
+                    counted: is_counted(&public_key),
+                    local: public_key == self.public_key,
+                    public_key,
+                    value: signer_value.to_string(),
+                    window_end: signer_window_end,
+                    deviation_bps,
+                }
+            })
+            .collect();
+
+        let quorum = self.quorum();
+        let agreeing = attestations
+            .iter()
+            .filter(|attestation| attestation.counted && attestation.agrees)
+            .count();
+
+        Ok(Consensus {
+            pair_id: pair_id.to_string(),
+            period,
+            aggregation,
+            window_end,
+            decimals: payload.decimals,
+            value: value.to_string(),
+            local_value: local_value.to_string(),
+            agreeing,
+            signers: quorum.map_or(1, |(signers, _)| signers),
+            threshold: quorum.map(|(_, threshold)| threshold),
+            authenticated: quorum.is_some(),
+            reached: matches!(quorum, Some((_, threshold)) if agreeing >= threshold),
+            tolerance_bps: self.config.tolerance_bps,
+            attestations,
+        })
+    }
+
+    /// Signs `payload` locally, asks the peers to co-sign it and collects the
+    /// signatures of the configured signers until the threshold is reached or
+    /// the signature timeout passes. Requires a configured signer set.
+    pub async fn gather_signatures(
+        &self,
+        payload: &TwapPayload,
+        p2p_sender: &UnboundedSender<P2PCommand>,
+    ) -> Result<SignatureBundle, PricingError> {
+        let (signers, threshold) = self.quorum().ok_or_else(|| {
+            PricingError::Unavailable(
+                "No signers configured, multi-signed TWAPs are unavailable".to_string(),
+            )
+        })?;
+
+        let local = TwapMessage {
+            payload: payload.clone(),
+            scheme: self.signing_service.scheme(),
+            signature: self.signing_service.sign_payload(payload)?,
+            public_key: self.signing_service.get_public_key().to_string(),
+        };
+        let mut signatures = vec![PeerSignature {
+            public_key: self.public_key.clone(),
+            scheme: local.scheme,
+            signature: local.signature.clone(),
+        }];
+
+        let (responses, mut receiver) = mpsc::unbounded_channel();
+        let request = SignatureRequest {
+            request_id: self.signing_service.message_hash(payload)?,
+            message: local,
+        };
+        p2p_sender
+            .send(P2PCommand::RequestSignatures { request, responses })
+            .map_err(|e| anyhow::anyhow!("Failed to request signatures: {}", e))?;
+
+        let known: HashSet<&str> = self.config.signers.iter().map(String::as_str).collect();
+        let deadline =
+            tokio::time::Instant::now() + Duration::from_millis(self.config.signature_timeout);
+        while signatures.len() < threshold {
+            let response = match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(response)) => response,
+                Ok(None) | Err(_) => break,
+            };
+            // The P2P service already checked the signature over the response's payload
+            let TwapMessage {
+                payload: signed_payload,
+                scheme,
+                signature,
+                public_key,
+            } = response.message;
+            let public_key = public_key.to_lowercase();
+            if signed_payload != *payload
+                || !known.contains(public_key.as_str())
+                || signatures
+                    .iter()
+                    .any(|signature| signature.public_key == public_key)
+            {
+                continue;
+            }
+            signatures.push(PeerSignature {
+                public_key,
+                scheme,
+                signature,
+            });
+        }
+
+        Ok(SignatureBundle {
+            reached: signatures.len() >= threshold,
+            signatures,
+            signers,
+            threshold,
+        })
+    }
+
+    /// Signs a peer's payload if the locally computed value for the same window
+    /// is within the tolerance of it
+    pub async fn cosign(
+        &self,
+        request: &SignatureRequest,
+    ) -> Result<SignatureResponse, PricingError> {
+        let payload = &request.message.payload;
+        let requested: BigInt = payload.twap.parse().map_err(|e| {
+            PricingError::InvalidRequest(format!("Invalid value {}: {}", payload.twap, e))
+        })?;
+        let local_value = self
+            .pricing
+            .reproduce(payload, self.config.window_tolerance)
+            .await?;
+
+        let agrees = match aggregation::deviation_bps(&requested, &local_value) {
+            Some(deviation) => deviation.abs() <= self.config.tolerance_bps as f64,
+            None => requested == local_value,
+        };
+        if !agrees {
+            return Err(PricingError::InvalidRequest(format!(
+                "value {} deviates from the local value {}",
+                requested, local_value
+            )));
+        }
+
+        Ok(SignatureResponse {
+            request_id: request.request_id.clone(),
+            message: TwapMessage {
+                payload: payload.clone(),
+                scheme: self.signing_service.scheme(),
+                signature: self.signing_service.sign_payload(payload)?,
+                public_key: self.signing_service.get_public_key().to_string(),
+            },
+        })
+    }
+
+    /// Lowercased public key of this node
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Number of signers and the agreeing signers required among them, or `None`
+    /// without a configured signer set: anyone can generate keys, so counting
+    /// every signer seen would let them take the quorum
+    fn quorum(&self) -> Option<(usize, usize)> {
+        let signers = &self.config.signers;
+        if signers.is_empty() {
+            return None;
+        }
+        let signers = signers.len() + usize::from(!signers.contains(&self.public_key));
+        Some((signers, self.config.threshold.unwrap_or(signers / 2 + 1)))
+    }
+}
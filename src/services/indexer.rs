@@ -1,7 +1,10 @@
 use core::time;
 
-use crate::config::{Config, SUBMITTED_SPOT_ENTRY_SELECTOR};
+use crate::config::{Config, EventKind, MAX_REORG_DEPTH};
 use crate::services::redis_client::RedisClient;
+use crate::types::checkpoint::Checkpoint;
+use crate::types::future_entry::FutureEntry;
+use crate::types::publisher_event::PublisherEvent;
 use crate::types::spot_entry::SpotEntry;
 use anyhow::Result;
 use apibara_core::starknet::v1alpha2::Event;
@@ -9,80 +12,276 @@ use apibara_core::{
     node::v1alpha2::DataFinality,
     starknet::v1alpha2::{Block, FieldElement, Filter, HeaderFilter},
 };
-use apibara_sdk::{configuration, ClientBuilder, Configuration, Uri};
+use apibara_sdk::{configuration, ClientBuilder, Configuration, DataMessage, Uri};
 use futures_util::TryStreamExt;
 use num_bigint::BigInt;
+use serde::Serialize;
 use starknet::core::types::Felt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
 
 const INDEXING_STREAM_CHUNK_SIZE: usize = 1;
 
+// Reconnection delay doubles after each consecutive failure, up to the maximum
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexerStatus {
+    Connecting,
+    Connected,
+    /// Connected, but the last indexed block is older than the allowed lag
+    Lagging,
+    Reconnecting,
+    /// Gave up after too many consecutive failures
+    Failed,
+}
+
+/// Indexer progress shared with the rest of the process
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexerState {
+    pub status: IndexerStatus,
+    pub head_block: Option<u64>,
+    pub head_block_timestamp: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl Default for IndexerState {
+    fn default() -> Self {
+        IndexerState {
+            status: IndexerStatus::Connecting,
+            head_block: None,
+            head_block_timestamp: None,
+            consecutive_failures: 0,
+            last_error: None,
+        }
+    }
+}
+
+impl IndexerState {
+    /// Seconds between `now` and the timestamp of the last indexed block
+    pub fn lag(&self, now: u64) -> Option<u64> {
+        self.head_block_timestamp
+            .map(|timestamp| now.saturating_sub(timestamp))
+    }
+
+    /// Status as seen at `now`: a connected indexer whose head is older than
+    /// `max_lag` seconds is reported as lagging
+    pub fn status_at(&self, now: u64, max_lag: u64) -> IndexerStatus {
+        match (self.status, self.lag(now)) {
+            (IndexerStatus::Connected, Some(lag)) if lag > max_lag => IndexerStatus::Lagging,
+            (status, _) => status,
+        }
+    }
+}
+
+pub type SharedIndexerState = Arc<RwLock<IndexerState>>;
+
 pub struct Indexer {
     config: Config,
     redis_client: RedisClient,
+    state: SharedIndexerState,
 }
 
 impl Indexer {
-    pub fn new(config: Config, redis_client: RedisClient) -> Self {
+    pub fn new(config: Config, redis_client: RedisClient, state: SharedIndexerState) -> Self {
         Indexer {
             config,
             redis_client,
+            state,
         }
     }
 
-    /// Start indexing SubmittedSpotEntry events
+    /// Start indexing the configured Pragma events, reconnecting with exponential
+    /// backoff and resuming from the stored cursor whenever the stream fails
     pub async fn run(&self) -> Result<()> {
-        let stream_config = Configuration::<Filter>::default()
-            .with_starting_block(self.config.starting_block)
+        if self.config.rescan_from_block.is_some() {
+            println!("Rescan requested, ignoring stored cursor");
+            self.redis_client.store_cursor(None).await?;
+        }
+
+        loop {
+            let error = match self.stream().await {
+                Ok(()) => anyhow::anyhow!("Stream closed by the server"),
+                Err(e) => e,
+            };
+
+            let failures = {
+                let mut state = self.state.write().await;
+                state.consecutive_failures += 1;
+                state.last_error = Some(error.to_string());
+
+                let max_retries = self.config.indexer_max_retries;
+                if max_retries > 0 && state.consecutive_failures > max_retries {
+                    state.status = IndexerStatus::Failed;
+                    eprintln!("Indexer giving up after {} attempts", max_retries);
+                    return Err(error);
+                }
+                state.status = IndexerStatus::Reconnecting;
+                state.consecutive_failures
+            };
+
+            let delay = INITIAL_RECONNECT_DELAY
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(MAX_RECONNECT_DELAY);
+            println!(
+                "Indexer stream failed ({}), reconnecting in {:?}",
+                error, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Streams from the stored cursor until the connection fails
+    async fn stream(&self) -> Result<()> {
+        let stream_config = Configuration::<Filter>::default();
+        let stored_cursor = self.redis_client.get_cursor().await?;
+        // Entries past a stored cursor can only come from a pending block, which
+        // the first batch sends again
+        let mut after_pending = stored_cursor.is_some();
+        let (stream_config, starting_point) = match stored_cursor {
+            Some(cursor) => {
+                let starting_point = format!("stored cursor at block {}", cursor.order_key);
+                (stream_config.with_starting_cursor(cursor), starting_point)
+            }
+            None => {
+                let starting_block = self
+                    .config
+                    .rescan_from_block
+                    .unwrap_or(self.config.starting_block);
+                (
+                    stream_config.with_starting_block(starting_block),
+                    format!("block {}", starting_block),
+                )
+            }
+        };
+        let targets = self.event_targets()?;
+        let stream_config = stream_config
             .with_finality(DataFinality::DataStatusPending)
             .with_filter(|mut filter| {
-                filter
-                    .with_header(HeaderFilter::weak())
-                    .add_event(|event| {
+                filter.with_header(HeaderFilter::weak());
+                for target in &targets {
+                    filter.add_event(|event| {
                         event
-                            .with_from_address(
-                                FieldElement::from_hex(&self.config.contract_address).unwrap(),
-                            )
-                            .with_keys(vec![
-                                FieldElement::from_hex(SUBMITTED_SPOT_ENTRY_SELECTOR).unwrap()
-                            ])
-                    })
-                    .build()
+                            .with_from_address(target.contract_address.clone())
+                            .with_keys(vec![target.selector.clone()])
+                    });
+                }
+                filter.build()
             });
 
         let (config_client, config_stream) = configuration::channel(INDEXING_STREAM_CHUNK_SIZE);
         config_client.send(stream_config.clone()).await?;
 
+        self.state.write().await.status = IndexerStatus::Connecting;
+
         let mut stream = ClientBuilder::default()
             .with_bearer_token(Some(self.config.apibara_api_key.clone()))
-            .connect(Uri::from_static("https://sepolia.starknet.a5a.ch"))
+            .connect(self.config.apibara_uri.parse::<Uri>()?)
             .await
-            .unwrap()
+            .map_err(|e| anyhow::anyhow!("Failed to connect to Apibara: {}", e))?
             .start_stream::<Filter, Block, _>(config_stream)
             .await
-            .unwrap();
+            .map_err(|e| anyhow::anyhow!("Failed to start stream: {}", e))?;
 
+        self.state.write().await.status = IndexerStatus::Connected;
         println!(
-            "🔍 Started indexing from block {}",
-            self.config.starting_block
+            "🔍 Started indexing {} from {}",
+            self.config.network.name(),
+            starting_point
         );
 
         loop {
             match stream.try_next().await {
-                Ok(Some(response)) => {
-                    if let apibara_sdk::DataMessage::Data { batch, .. } = response {
+                Ok(Some(response)) => match response {
+                    DataMessage::Data {
+                        cursor,
+                        end_cursor,
+                        finality,
+                        batch,
+                    } => {
+                        // A batch following a pending one replaces everything after its
+                        // starting cursor, which drops entries from the pending block that
+                        // was since re-sent or replaced
+                        if after_pending {
+                            if let Some(cursor) = cursor.as_ref() {
+                                self.rollback(cursor.order_key).await?;
+                            }
+                        }
+                        after_pending = finality == DataFinality::DataStatusPending;
+
+                        let mut head_block_timestamp = None;
                         for block in batch {
+                            if let Some(header) = block.header.as_ref() {
+                                if let Some(timestamp) = header.timestamp.as_ref() {
+                                    let timestamp = timestamp.seconds.max(0) as u64;
+                                    self.redis_client
+                                        .store_block_timestamp(header.block_number, timestamp)
+                                        .await?;
+                                    head_block_timestamp = Some(timestamp);
+                                }
+                            }
                             for event in block.events {
+                                let transaction_hash = event
+                                    .transaction
+                                    .as_ref()
+                                    .and_then(|transaction| transaction.meta.as_ref())
+                                    .and_then(|meta| meta.hash.as_ref())
+                                    .map(|hash| {
+                                        format!("{:#x}", Felt::from_bytes_be(&hash.to_bytes()))
+                                    });
                                 if let Some(event) = event.event {
                                     let block_number =
                                         block.header.clone().map(|h| h.block_number).unwrap_or(0);
 
-                                    self.handle_event(block_number, event).await?;
+                                    self.handle_event(
+                                        &targets,
+                                        block_number,
+                                        transaction_hash.as_deref(),
+                                        event,
+                                    )
+                                    .await?;
                                 }
                             }
                         }
+
+                        self.redis_client
+                            .prune_block_index(end_cursor.order_key.saturating_sub(MAX_REORG_DEPTH))
+                            .await?;
+
+                        // A pending block will be sent again, so only resume after it once accepted
+                        if finality == DataFinality::DataStatusPending {
+                            self.redis_client.store_cursor(cursor.as_ref()).await?;
+                        } else {
+                            self.redis_client.store_cursor(Some(&end_cursor)).await?;
+                        }
+
+                        let mut state = self.state.write().await;
+                        state.status = IndexerStatus::Connected;
+                        state.consecutive_failures = 0;
+                        state.last_error = None;
+                        state.head_block = Some(end_cursor.order_key);
+                        if head_block_timestamp.is_some() {
+                            state.head_block_timestamp = head_block_timestamp;
+                        }
                     }
-                }
-                Ok(None) => continue,
+                    DataMessage::Invalidate { cursor } => {
+                        let block_number = cursor.as_ref().map(|c| c.order_key).unwrap_or(0);
+                        println!(
+                            "Chain reorganization, invalidating blocks after {}",
+                            block_number
+                        );
+                        self.rollback(block_number).await?;
+                        after_pending = false;
+                        self.redis_client.store_cursor(cursor.as_ref()).await?;
+                        self.state.write().await.head_block = Some(block_number);
+                    }
+                    _ => {}
+                },
+                Ok(None) => return Ok(()),
                 Err(e) => {
                     println!("Error while streaming: {}", e);
                     return Err(anyhow::anyhow!("Streaming error: {}", e));
@@ -91,18 +290,93 @@ impl Indexer {
         }
     }
 
-    async fn handle_event(&self, block_number: u64, event: Event) -> Result<()> {
+    /// Removes stored entries that came from blocks after `block_number`
+    async fn rollback(&self, block_number: u64) -> Result<()> {
+        let removed = self.redis_client.rollback_after_block(block_number).await?;
+        if removed > 0 {
+            println!(
+                "Rolled back {} entries from blocks after {}",
+                removed, block_number
+            );
+        }
+        Ok(())
+    }
+
+    /// Expands the configured event sources into one (contract, selector) pair per event
+    fn event_targets(&self) -> Result<Vec<EventTarget>> {
+        let mut targets = Vec::new();
+        for source in &self.config.event_sources {
+            let contract_address = FieldElement::from_hex(&source.contract_address)
+                .map_err(|e| anyhow::anyhow!("Invalid contract address: {:?}", e))?;
+            for selector in source.kind.selectors() {
+                targets.push(EventTarget {
+                    contract_address: contract_address.clone(),
+                    selector: FieldElement::from_hex(selector)
+                        .map_err(|e| anyhow::anyhow!("Invalid event selector: {:?}", e))?,
+                    kind: source.kind,
+                });
+            }
+        }
+        Ok(targets)
+    }
+
+    async fn handle_event(
+        &self,
+        targets: &[EventTarget],
+        block_number: u64,
+        transaction_hash: Option<&str>,
+        event: Event,
+    ) -> Result<()> {
         if event.from_address.is_none() || event.data.is_empty() {
             return Ok(());
         }
 
-        if let Some(entry) = SpotEntry::from_event(&event, block_number) {
-            // println!("Block: {}", block_number);
-            // println!("Storing entry for pair: {}", entry.pair_id);
+        let kind = targets.iter().find_map(|target| {
+            (event.from_address.as_ref() == Some(&target.contract_address)
+                && event.keys.first() == Some(&target.selector))
+            .then_some(target.kind)
+        });
 
-            // Store in Redis
-            self.redis_client.store_spot_entry(&entry).await?;
+        // Store in Redis
+        match kind {
+            Some(EventKind::SpotEntry) => {
+                // Spot entries are identified by their transaction hash, and storing
+                // one without it could replace another entry of the same block
+                let Some(transaction_hash) = transaction_hash else {
+                    eprintln!(
+                        "Skipping spot entry in block {} without a transaction hash",
+                        block_number
+                    );
+                    return Ok(());
+                };
+                if let Some(entry) = SpotEntry::from_event(&event, block_number, transaction_hash) {
+                    self.redis_client.store_spot_entry(&entry).await?;
+                }
+            }
+            Some(EventKind::FutureEntry) => {
+                if let Some(entry) = FutureEntry::from_event(&event, block_number) {
+                    self.redis_client.store_future_entry(&entry).await?;
+                }
+            }
+            Some(EventKind::Checkpoint) => {
+                if let Some(checkpoint) = Checkpoint::from_event(&event, block_number) {
+                    self.redis_client.store_checkpoint(&checkpoint).await?;
+                }
+            }
+            Some(EventKind::PublisherRegistry) => {
+                if let Some(change) = PublisherEvent::from_event(&event, block_number) {
+                    self.redis_client.store_publisher_event(&change).await?;
+                }
+            }
+            None => {}
         }
         Ok(())
     }
 }
+
+/// An event the stream subscribes to and the decoder it is routed to
+struct EventTarget {
+    contract_address: FieldElement,
+    selector: FieldElement,
+    kind: EventKind,
+}
@@ -1,7 +1,11 @@
+pub mod aggregation;
+pub mod consensus;
 pub mod indexer;
 pub use indexer::Indexer;
 pub mod redis_client;
+pub mod retention;
 pub use redis_client::RedisClient;
 pub mod signing;
 pub use signing::SigningService;
 pub mod p2p;
+pub mod pricing;
@@ -1,31 +1,159 @@
 // src/services/p2p.rs
 
-use anyhow::Result;
+use crate::config::{
+    P2PConfig, COSIGN_RATE_WINDOW, COSIGN_REQUESTS_PER_SIGNER, MAX_CONCURRENT_COSIGNS,
+};
+use crate::services::consensus::ConsensusService;
+use crate::services::redis_client::RedisClient;
+use crate::services::signing::SigningScheme;
+use crate::services::SigningService;
+use crate::types::twap_payload::TwapPayload;
+use anyhow::{Context, Result};
 use futures::StreamExt;
 use libp2p::{
     core::{self, transport::Transport, upgrade},
-    gossipsub::{self, IdentTopic, MessageAuthenticity, ValidationMode},
+    gossipsub::{self, IdentTopic, MessageAcceptance, MessageAuthenticity, ValidationMode},
     identity, mdns, noise,
     swarm::{self, NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, StreamProtocol,
+    tcp, yamux, PeerId, StreamProtocol,
 };
-use num_bigint::BigInt;
-use secp256k1::{Message, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TwapMessage {
-    pub pair_id: String,
-    pub twap: String,
-    pub period: u64,
+    #[serde(flatten)]
+    pub payload: TwapPayload,
+    pub scheme: SigningScheme,
     pub signature: String,
-    pub timestamp: u64,
     pub public_key: String,
 }
 
+/// Asks peers to co-sign a payload priced and signed by the requesting node
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignatureRequest {
+    pub request_id: String,
+    #[serde(flatten)]
+    pub message: TwapMessage,
+}
+
+/// A peer's signature over the payload of a `SignatureRequest`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignatureResponse {
+    pub request_id: String,
+    #[serde(flatten)]
+    pub message: TwapMessage,
+}
+
+/// Work handed to the P2P service by the rest of the node
+#[derive(Debug)]
+pub enum P2PCommand {
+    /// Gossip a signed TWAP to every peer
+    Broadcast(TwapMessage),
+    /// Ask peers to co-sign a payload, forwarding their signatures to `responses`
+    /// until it is dropped
+    RequestSignatures {
+        request: SignatureRequest,
+        responses: mpsc::UnboundedSender<SignatureResponse>,
+    },
+}
+
+/// A gossiped message that passed validation
+enum Gossip {
+    Twap(TwapMessage),
+    SignatureRequest(SignatureRequest),
+    SignatureResponse(SignatureResponse),
+}
+
+/// Why a gossiped message is dropped
+enum Dropped {
+    /// Malformed, stale, badly signed, for another network or from an untrusted
+    /// signer; the peer is penalized
+    Invalid(String),
+    /// Valid but of no use to this node, such as a duplicate
+    Irrelevant(String),
+}
+
+/// What makes a signed TWAP unique; a replayed message has the same key
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SeenKey {
+    /// First, so seen keys are ordered by signing time and expire in order
+    timestamp: u64,
+    kind: &'static str,
+    public_key: String,
+    pair_id: String,
+    period: u64,
+    aggregation: String,
+    window_end: u64,
+}
+
+/// Drops gossiped messages signed outside the clock-skew window or over an
+/// inconsistent window, and the ones already seen within it. Older messages are rejected outright, so only the
+/// window needs to be remembered.
+struct ReplayGuard {
+    max_clock_skew: u64,
+    seen: BTreeSet<SeenKey>,
+}
+
+impl ReplayGuard {
+    fn new(max_clock_skew: u64) -> Self {
+        ReplayGuard {
+            max_clock_skew,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    fn check_fresh(&self, message: &TwapMessage, now: u64) -> Result<(), Dropped> {
+        let timestamp = message.payload.timestamp;
+        if timestamp.abs_diff(now) > self.max_clock_skew {
+            return Err(Dropped::Invalid(format!(
+                "Signed at {}, more than {}s away from the local time {}",
+                timestamp, self.max_clock_skew, now
+            )));
+        }
+
+        // Attestations are stored and trimmed by window end, which must not be
+        // later than the signing time allows
+        let payload = &message.payload;
+        if payload.window_end > timestamp + self.max_clock_skew {
+            return Err(Dropped::Invalid(format!(
+                "Window ends at {}, after its signing time {}",
+                payload.window_end, timestamp
+            )));
+        }
+        if payload.window_end.saturating_sub(payload.period) != payload.window_start {
+            return Err(Dropped::Invalid(format!(
+                "Window [{}, {}] does not span its period {}",
+                payload.window_start, payload.window_end, payload.period
+            )));
+        }
+        Ok(())
+    }
+
+    /// Remembers a message, returning false if it was already seen
+    fn record(&mut self, kind: &'static str, message: &TwapMessage, now: u64) -> bool {
+        let cutoff = now.saturating_sub(self.max_clock_skew);
+        while matches!(self.seen.first(), Some(oldest) if oldest.timestamp < cutoff) {
+            self.seen.pop_first();
+        }
+
+        self.seen.insert(SeenKey {
+            timestamp: message.payload.timestamp,
+            kind,
+            public_key: message.public_key.to_lowercase(),
+            pair_id: message.payload.pair_id.clone(),
+            period: message.payload.period,
+            aggregation: message.payload.aggregation.to_string(),
+            window_end: message.payload.window_end,
+        })
+    }
+}
+
 // Define our network behaviour
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
@@ -37,111 +165,343 @@ pub struct P2PService {
     peer_id: PeerId,
     swarm: swarm::Swarm<MyBehaviour>,
     topics: Vec<IdentTopic>,
+    request_topic: IdentTopic,
+    signature_topic: IdentTopic,
+    network: String,
+    /// Signers whose messages are accepted, any signer when empty
+    trusted_signers: HashSet<String>,
+    replay_guard: ReplayGuard,
+    redis_client: RedisClient,
+    consensus: ConsensusService,
+    /// Signature requests of this node still waiting for responses
+    pending: HashMap<String, Vec<mpsc::UnboundedSender<SignatureResponse>>>,
+    /// Bounds the co-signatures priced in the background at once
+    cosign_permits: Arc<Semaphore>,
+    /// Start of the current rate window and requests made in it, per signer
+    cosign_requests: HashMap<String, (u64, u32)>,
 }
 
 impl P2PService {
-    pub async fn new(listen_addr: Multiaddr, bootstrap_peers: Vec<Multiaddr>) -> Result<Self> {
-        // Create a random key for our identity
-        let id_keys = identity::Keypair::generate_ed25519();
+    pub async fn new(
+        config: P2PConfig,
+        network: &str,
+        redis_client: RedisClient,
+        consensus: ConsensusService,
+    ) -> Result<Self> {
+        // The same identity authenticates the transport and signs gossip, so the
+        // peer ID other operators pin is the one they see on the wire
+        let id_keys = match &config.key_file {
+            Some(path) => load_or_create_identity(path)?,
+            None => {
+                println!("No P2P_KEY_FILE configured, using a new peer id for this run");
+                identity::Keypair::generate_ed25519()
+            }
+        };
         let peer_id = PeerId::from(id_keys.public());
         println!("Local peer id: {:?}", peer_id);
 
         // Set up an encrypted TCP transport over yamux
         let tcp_transport = tcp::tokio::Transport::default();
-        let noise_keys = identity::Keypair::generate_ed25519();
 
         // Use with_tokio_executor to ensure proper thread safety
         let transport = tcp_transport
             .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&noise_keys).unwrap())
+            .authenticate(noise::Config::new(&id_keys).context("Invalid noise keys")?)
             .multiplex(yamux::Config::default())
             .boxed();
 
-        // Set up gossipsub with proper tokio compatibility
+        // One topic per network so nodes on different networks never exchange prices
+        let topics = vec![IdentTopic::new(format!("twap-updates/{}", network))];
+        let request_topic = IdentTopic::new(format!("twap-signature-requests/{}", network));
+        let signature_topic = IdentTopic::new(format!("twap-signatures/{}", network));
+
+        // Set up gossipsub with proper tokio compatibility. Messages are only
+        // relayed once the application has validated them.
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(10))
             .validation_mode(ValidationMode::Strict)
+            .validate_messages()
             .build()
             .expect("Valid gossipsub config");
 
-        let gossipsub = gossipsub::Behaviour::new(
+        let mut gossipsub = gossipsub::Behaviour::new(
             MessageAuthenticity::Signed(id_keys.clone()),
             gossipsub_config,
         )
         .expect("Valid gossipsub params");
 
+        // Score peers so the ones relaying rejected messages get pruned and graylisted
+        let topic_score_params = gossipsub::TopicScoreParams {
+            // Prices are gossiped on demand, so a quiet peer is not a faulty one
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            ..Default::default()
+        };
+        let mut peer_score_params = gossipsub::PeerScoreParams::default();
+        for topic in topics.iter().chain([&request_topic, &signature_topic]) {
+            peer_score_params
+                .topics
+                .insert(topic.hash(), topic_score_params.clone());
+        }
+        gossipsub
+            .with_peer_score(peer_score_params, gossipsub::PeerScoreThresholds::default())
+            .map_err(|e| anyhow::anyhow!("Invalid peer score params: {}", e))?;
+
         // Use tokio-specific mDNS implementation
         let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?;
 
         let behaviour = MyBehaviour { gossipsub, mdns };
 
         // Create a swarm with tokio executor
-        let config = swarm::Config::with_tokio_executor();
-        let mut swarm = swarm::Swarm::new(transport, behaviour, peer_id, config);
+        let swarm_config = swarm::Config::with_tokio_executor();
+        let mut swarm = swarm::Swarm::new(transport, behaviour, peer_id, swarm_config);
 
         // Listen on the provided address
-        swarm.listen_on(listen_addr)?;
-
-        let topics = vec![IdentTopic::new("twap-updates")];
+        swarm.listen_on(config.listen_address)?;
 
         // Subscribe to topics
-        for topic in &topics {
+        for topic in topics.iter().chain([&request_topic, &signature_topic]) {
             swarm.behaviour_mut().gossipsub.subscribe(topic)?;
         }
 
         // Connect to bootstrap peers
-        for addr in bootstrap_peers {
+        for addr in config.bootstrap_peers {
             swarm.dial(addr)?;
         }
 
+        if config.trusted_signers.is_empty() {
+            eprintln!(
+                "WARNING: no trusted signers configured (P2P_TRUSTED_SIGNERS or \
+                 CONSENSUS_SIGNERS), accepting and relaying messages from any signer. \
+                 Anyone can generate a key and flood the network through this node."
+            );
+        }
+
         Ok(P2PService {
             peer_id,
             swarm,
             topics,
+            request_topic,
+            signature_topic,
+            network: network.to_string(),
+            trusted_signers: config.trusted_signers.into_iter().collect(),
+            replay_guard: ReplayGuard::new(config.max_clock_skew),
+            redis_client,
+            consensus,
+            pending: HashMap::new(),
+            cosign_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_COSIGNS)),
+            cosign_requests: HashMap::new(),
         })
     }
 
-    fn handle_twap_message(&self, message: TwapMessage) -> Result<()> {
-        // Verify the signature
-        let secp = Secp256k1::new();
-        let twap_bigint = BigInt::from(message.twap.parse::<i64>()?);
-        let message_str = twap_bigint.to_string();
-
-        let mut hasher = Sha256::new();
-        hasher.update(message_str.as_bytes());
-        let message_hash = hasher.finalize();
-        let signature = secp256k1::ecdsa::Signature::from_der(&hex::decode(&message.signature)?)?;
-        let public_key = secp256k1::PublicKey::from_slice(&hex::decode(&message.public_key)?)?;
-        let message = Message::from_slice(&message_hash)?;
-
-        match secp.verify_ecdsa(&message, &signature, &public_key) {
-            Ok(_) => {
-                println!("Valid signature from peer");
-                // Process the verified message
-                Ok(())
+    /// Decodes a gossiped message according to its topic, checks its signature
+    /// and drops stale or replayed ones
+    fn validate(&mut self, message: &gossipsub::Message) -> Result<Gossip, Dropped> {
+        let invalid = |e: serde_json::Error| Dropped::Invalid(format!("Malformed message: {}", e));
+        let gossip = if message.topic == self.request_topic.hash() {
+            Gossip::SignatureRequest(serde_json::from_slice(&message.data).map_err(invalid)?)
+        } else if message.topic == self.signature_topic.hash() {
+            Gossip::SignatureResponse(serde_json::from_slice(&message.data).map_err(invalid)?)
+        } else if self
+            .topics
+            .iter()
+            .any(|topic| topic.hash() == message.topic)
+        {
+            Gossip::Twap(serde_json::from_slice(&message.data).map_err(invalid)?)
+        } else {
+            return Err(Dropped::Irrelevant(format!(
+                "Unknown topic {}",
+                message.topic
+            )));
+        };
+
+        let (kind, twap_message) = match &gossip {
+            Gossip::Twap(message) => ("twap", message),
+            Gossip::SignatureRequest(request) => ("signature-request", &request.message),
+            Gossip::SignatureResponse(response) => ("signature-response", &response.message),
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        self.replay_guard.check_fresh(twap_message, now)?;
+        self.verify_twap_message(twap_message)?;
+        // Only recorded once the signature is known to be valid, so forged
+        // messages cannot shadow genuine ones
+        if !self.replay_guard.record(kind, twap_message, now) {
+            return Err(Dropped::Irrelevant(format!(
+                "Already seen {} from {}",
+                kind, twap_message.public_key
+            )));
+        }
+        if let Gossip::SignatureRequest(request) = &gossip {
+            let signer = request.message.public_key.to_lowercase();
+            if !self.allow_cosign_request(signer, now) {
+                return Err(Dropped::Irrelevant(format!(
+                    "Too many signature requests from {}",
+                    request.message.public_key
+                )));
+            }
+        }
+        Ok(gossip)
+    }
+
+    /// Counts a signature request against its signer's rate limit, returning
+    /// false once the signer made `COSIGN_REQUESTS_PER_SIGNER` in the window
+    fn allow_cosign_request(&mut self, signer: String, now: u64) -> bool {
+        self.cosign_requests
+            .retain(|_, (window_start, _)| *window_start + COSIGN_RATE_WINDOW > now);
+        let (_, requests) = self.cosign_requests.entry(signer).or_insert((now, 0));
+        *requests += 1;
+        *requests <= COSIGN_REQUESTS_PER_SIGNER
+    }
+
+    /// Checks that a TWAP is for this network, validly signed, and signed by a
+    /// trusted signer when any is configured
+    fn verify_twap_message(&self, message: &TwapMessage) -> Result<(), Dropped> {
+        if message.payload.network != self.network {
+            return Err(Dropped::Invalid(format!(
+                "TWAP for network {} received on {}",
+                message.payload.network, self.network
+            )));
+        }
+
+        let public_key = message.public_key.to_lowercase();
+        if public_key == self.consensus.public_key() {
+            return Err(Dropped::Irrelevant("Signed by this node".to_string()));
+        }
+
+        // Verify the signature over the full payload
+        SigningService::verify_payload(
+            &message.payload,
+            message.scheme,
+            &message.signature,
+            &message.public_key,
+        )
+        .map_err(|e| Dropped::Invalid(format!("Invalid signature: {}", e)))?;
+
+        // Penalizes peers flooding messages under fresh keys
+        if !self.trusted_signers.is_empty() && !self.trusted_signers.contains(&public_key) {
+            return Err(Dropped::Invalid(format!("Untrusted signer {}", public_key)));
+        }
+        Ok(())
+    }
+
+    async fn handle_gossip(
+        &mut self,
+        gossip: Gossip,
+        cosigned: &mpsc::UnboundedSender<SignatureResponse>,
+    ) {
+        match gossip {
+            Gossip::Twap(message) => {
+                // Stored for consensus checks
+                match self.redis_client.store_attestation(&message).await {
+                    Ok(_) => println!(
+                        "Verified and stored TWAP attestation for pair_id: {}",
+                        message.payload.pair_id
+                    ),
+                    Err(e) => println!("Failed to store TWAP attestation: {}", e),
+                }
+            }
+            Gossip::SignatureRequest(request) => {
+                self.handle_signature_request(request, cosigned.clone())
+            }
+            Gossip::SignatureResponse(response) => self.handle_signature_response(response),
+        }
+    }
+
+    /// Co-signs a peer's payload in the background if this node computes a
+    /// matching value, sending the signature to `cosigned` for publishing.
+    /// Requests beyond `MAX_CONCURRENT_COSIGNS` in flight are dropped rather
+    /// than queued, so pricing never holds up the swarm.
+    fn handle_signature_request(
+        &self,
+        request: SignatureRequest,
+        cosigned: mpsc::UnboundedSender<SignatureResponse>,
+    ) {
+        let permit = match self.cosign_permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                println!(
+                    "Too many co-signatures in flight, dropping request {}",
+                    request.request_id
+                );
+                return;
+            }
+        };
+        let consensus = self.consensus.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let pair_id = &request.message.payload.pair_id;
+            match consensus.cosign(&request).await {
+                Ok(response) => {
+                    println!(
+                        "Co-signed {} TWAP for request {}",
+                        pair_id, request.request_id
+                    );
+                    let _ = cosigned.send(response);
+                }
+                Err(e) => println!(
+                    "Declined to co-sign {} TWAP for request {}: {}",
+                    pair_id, request.request_id, e
+                ),
+            }
+        });
+    }
+
+    /// Forwards a peer's signature to the local requests waiting for it
+    fn handle_signature_response(&mut self, response: SignatureResponse) {
+        if let Some(senders) = self.pending.get_mut(&response.request_id) {
+            senders.retain(|sender| sender.send(response.clone()).is_ok());
+            if senders.is_empty() {
+                self.pending.remove(&response.request_id);
             }
+        }
+    }
+
+    fn publish<T: Serialize>(&mut self, topic: IdentTopic, message: &T) {
+        let data = match serde_json::to_vec(message) {
+            Ok(data) => data,
             Err(e) => {
-                println!("Invalid signature from peer: {}", e);
-                Err(anyhow::anyhow!("Invalid signature"))
+                println!("Serialization error: {}", e);
+                return;
             }
+        };
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+            println!("Publishing error: {}", e);
         }
     }
 
     pub async fn run(
         mut self,
-        mut message_receiver: mpsc::UnboundedReceiver<TwapMessage>,
+        mut command_receiver: mpsc::UnboundedReceiver<P2PCommand>,
     ) -> Result<()> {
+        // Co-signatures priced in the background, published from this loop
+        let (cosign_sender, mut cosigned) = mpsc::unbounded_channel();
         loop {
             tokio::select! {
-                Some(message) = message_receiver.recv() => {
-                    let data = serde_json::to_string(&message)?;
-                    // Broadcast to all topics
-                    for topic in &self.topics {
-                        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
-                            topic.clone(),
-                            data.clone().as_bytes(),
-                        ) {
-                            println!("Publishing error: {}", e);
+                Some(response) = cosigned.recv() => {
+                    self.publish(self.signature_topic.clone(), &response);
+                }
+                Some(command) = command_receiver.recv() => {
+                    match command {
+                        P2PCommand::Broadcast(message) => {
+                            // Broadcast to all topics
+                            for topic in self.topics.clone() {
+                                self.publish(topic, &message);
+                            }
+                        }
+                        P2PCommand::RequestSignatures { request, responses } => {
+                            // Forget requests whose callers stopped waiting
+                            self.pending.retain(|_, senders| {
+                                senders.retain(|sender| !sender.is_closed());
+                                !senders.is_empty()
+                            });
+                            self.pending
+                                .entry(request.request_id.clone())
+                                .or_default()
+                                .push(responses);
+                            self.publish(self.request_topic.clone(), &request);
                         }
                     }
                 }
@@ -167,17 +527,29 @@ impl P2PService {
                                         peer_id
                                     );
 
-                                    if let Ok(twap_message) = serde_json::from_slice::<TwapMessage>(&message.data) {
-                                        match self.handle_twap_message(twap_message.clone()) {
-                                            Ok(_) => {
-                                                let pair_id = twap_message.pair_id;
-                                                println!(
-                                                    "Verified and received TWAP update for pair_id: {}",
-                                                    pair_id
-                                                )
-                                            },
-                                            Err(e) => println!("Message verification failed: {}", e),
+                                    // Report the outcome before handling, so valid
+                                    // messages are relayed without waiting on Redis
+                                    let (acceptance, gossip) = match self.validate(&message) {
+                                        Ok(gossip) => (MessageAcceptance::Accept, Some(gossip)),
+                                        Err(Dropped::Invalid(reason)) => {
+                                            println!("Rejected message {} from {}: {}", id, peer_id, reason);
+                                            (MessageAcceptance::Reject, None)
                                         }
+                                        Err(Dropped::Irrelevant(reason)) => {
+                                            println!("Ignored message {} from {}: {}", id, peer_id, reason);
+                                            (MessageAcceptance::Ignore, None)
+                                        }
+                                    };
+                                    if let Err(e) = self
+                                        .swarm
+                                        .behaviour_mut()
+                                        .gossipsub
+                                        .report_message_validation_result(&id, &peer_id, acceptance)
+                                    {
+                                        println!("Failed to report validation of {}: {}", id, e);
+                                    }
+                                    if let Some(gossip) = gossip {
+                                        self.handle_gossip(gossip, &cosign_sender).await;
                                     }
                                 }
                                 _ => {}
@@ -194,3 +566,37 @@ impl P2PService {
         &self.peer_id
     }
 }
+
+/// Reads the node identity from `path`, or generates one and saves it there on
+/// first start
+fn load_or_create_identity(path: &Path) -> Result<identity::Keypair> {
+    if path.exists() {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read P2P key file {}", path.display()))?;
+        return identity::Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("Invalid P2P key file {}", path.display()));
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    let bytes = keypair
+        .to_protobuf_encoding()
+        .context("Failed to encode P2P key")?;
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    // Only readable by the node, the key is its identity on the network
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&bytes))
+        .with_context(|| format!("Failed to write P2P key file {}", path.display()))?;
+    println!("Created P2P key file {}", path.display());
+    Ok(keypair)
+}
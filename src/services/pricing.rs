@@ -0,0 +1,513 @@
+use crate::config::Config;
+use crate::services::aggregation::{
+    self, Aggregate, AggregationMode, BreakdownKey, Contribution, WindowStats,
+};
+use crate::services::indexer::{IndexerState, SharedIndexerState};
+use crate::services::redis_client::RedisClient;
+use crate::types::twap_payload::{TwapPayload, TWAP_PAYLOAD_VERSION};
+use num_bigint::BigInt;
+use serde::Serialize;
+use std::fmt;
+
+/// Where the aggregation window ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowEnd {
+    Now,
+    Timestamp(u64),
+    /// Timestamp of the given block, ignoring entries from later blocks
+    Block(u64),
+}
+
+/// Freshness information about a TWAP, not covered by the signature
+#[derive(Debug, Clone, Serialize)]
+pub struct TwapMetadata {
+    #[serde(flatten)]
+    pub stats: WindowStats,
+    pub end_block: Option<u64>,
+    pub indexer_head_block: Option<u64>,
+    pub indexer_lag: Option<u64>,
+    /// Prices a derived value was computed from
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub constituents: Vec<ConstituentPrice>,
+}
+
+/// Aggregate of one of the pairs a derived price was computed from
+#[derive(Debug, Clone, Serialize)]
+pub struct ConstituentPrice {
+    pub pair_id: String,
+    pub value: String,
+    pub decimals: u32,
+    #[serde(flatten)]
+    pub stats: WindowStats,
+}
+
+/// A window resolved to timestamps
+struct Window {
+    start: u64,
+    end: u64,
+    /// Entries from later blocks are ignored
+    end_block: Option<u64>,
+    live: bool,
+    indexer_lag: Option<u64>,
+}
+
+/// An aggregated price ready to be signed
+#[derive(Debug, Clone)]
+pub struct PricedWindow {
+    pub payload: TwapPayload,
+    pub metadata: TwapMetadata,
+}
+
+/// Contributions of each source or publisher to the aggregate of a window
+#[derive(Debug, Clone)]
+pub struct Breakdown {
+    pub window_start: u64,
+    pub window_end: u64,
+    pub end_block: Option<u64>,
+    pub aggregate: String,
+    pub contributions: Vec<Contribution>,
+}
+
+#[derive(Debug)]
+pub enum PricingError {
+    InvalidRequest(String),
+    NotFound(String),
+    /// Data exists but is too old or too sparse to trust
+    Stale(String),
+    /// The node is not configured to serve the request
+    Unavailable(String),
+    Internal(anyhow::Error),
+}
+
+impl fmt::Display for PricingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PricingError::InvalidRequest(reason)
+            | PricingError::NotFound(reason)
+            | PricingError::Stale(reason)
+            | PricingError::Unavailable(reason) => f.write_str(reason),
+            PricingError::Internal(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for PricingError {
+    fn from(e: anyhow::Error) -> Self {
+        PricingError::Internal(e)
+    }
+}
+
+/// Computes aggregated prices over live or historical windows from the indexed data
+#[derive(Clone)]
+pub struct PricingService {
+    config: Config,
+    redis_client: RedisClient,
+    indexer_state: SharedIndexerState,
+}
+
+impl PricingService {
+    pub fn new(
+        config: Config,
+        redis_client: RedisClient,
+        indexer_state: SharedIndexerState,
+    ) -> Self {
+        PricingService {
+            config,
+            redis_client,
+            indexer_state,
+        }
+    }
+
+    /// Aggregates `pair_id` over the window of `period` seconds ending at `end`.
+    /// A pair without data of its own is derived from its base and quote priced
+    /// in the configured quote currency, e.g. ETH/BTC from ETH/USD and BTC/USD.
+    pub async fn price(
+        &self,
+        pair_id: &str,
+        period: u64,
+        aggregation: AggregationMode,
+        end: WindowEnd,
+    ) -> Result<PricedWindow, PricingError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(anyhow::Error::from)?
+            .as_secs();
+        let indexer = self.indexer_state.read().await.clone();
+
+        let (window_end, end_block) = self.resolve_end(end, now, &indexer).await?;
+        let window = Window {
+            start: window_end.saturating_sub(period),
+            end: window_end,
+            end_block,
+            live: end == WindowEnd::Now,
+            indexer_lag: indexer.lag(now),
+        };
+
+        let (value, stats, constituents) =
+            match self.price_pair(pair_id, aggregation, &window).await {
+                Ok(aggregate) => (aggregate.value, aggregate.stats, Vec::new()),
+                Err(PricingError::NotFound(reason)) => match self.derivation_route(pair_id) {
+                    Some((base_pair, quote_pair)) => {
+                        self.derive(pair_id, &base_pair, &quote_pair, aggregation, &window)
+                            .await?
+                    }
+                    None => return Err(PricingError::NotFound(reason)),
+                },
+                Err(e) => return Err(e),
+            };
+
+        Ok(PricedWindow {
+            payload: TwapPayload {
+                version: TWAP_PAYLOAD_VERSION,
+                network: self.config.network.name().to_string(),
+                pair_id: pair_id.to_string(),
+                period,
+                window_start: window.start,
+                window_end: window.end,
+                timestamp: now,
+                aggregation,
+                decimals: self.config.decimals_for(pair_id),
+                twap: value.to_string(),
+                constituents: constituents
+                    .iter()
+                    .map(|constituent| constituent.pair_id.clone())
+                    .collect(),
+            },
+            metadata: TwapMetadata {
+                stats,
+                end_block,
+                indexer_head_block: indexer.head_block,
+                indexer_lag: window.indexer_lag,
+                constituents,
+            },
+        })
+    }
+
+    /// Recomputes the value of a payload priced by another node, over the same
+    /// window and route, to decide whether to co-sign it. A window ending within
+    /// `max_skew` seconds of now is priced as live, since the indexer has usually
+    /// not seen a block past its end yet.
+    pub async fn reproduce(
+        &self,
+        payload: &TwapPayload,
+        max_skew: u64,
+    ) -> Result<BigInt, PricingError> {
+        if payload.network != self.config.network.name() {
+            return Err(PricingError::InvalidRequest(format!(
+                "payload is for network {}",
+                payload.network
+            )));
+        }
+        if payload.decimals != self.config.decimals_for(&payload.pair_id) {
+            return Err(PricingError::InvalidRequest(format!(
+                "payload uses {} decimals for {}",
+                payload.decimals, payload.pair_id
+            )));
+        }
+        if payload.window_end.checked_sub(payload.period) != Some(payload.window_start) {
+            return Err(PricingError::InvalidRequest(
+                "window does not match the period".to_string(),
+            ));
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(anyhow::Error::from)?
+            .as_secs();
+        let indexer = self.indexer_state.read().await.clone();
+        if payload.window_end > now + max_skew {
+            return Err(PricingError::InvalidRequest(format!(
+                "window_end {} is in the future",
+                payload.window_end
+            )));
+        }
+        let live = payload.window_end + max_skew >= now;
+        if !live {
+            self.resolve_end(WindowEnd::Timestamp(payload.window_end), now, &indexer)
+                .await?;
+        }
+        let window = Window {
+            start: payload.window_start,
+            end: payload.window_end,
+            end_block: None,
+            live,
+            indexer_lag: indexer.lag(now),
+        };
+
+        if payload.constituents.is_empty() {
+            return Ok(self
+                .price_pair(&payload.pair_id, payload.aggregation, &window)
+                .await?
+                .value);
+        }
+        // Only the route this node would derive the pair through, so a peer
+        // cannot get another quantity, such as the inverse, signed under its name
+        match self.derivation_route(&payload.pair_id) {
+            Some((base_pair, quote_pair))
+                if payload.constituents == [base_pair.as_str(), quote_pair.as_str()] =>
+            {
+                Ok(self
+                    .derive(
+                        &payload.pair_id,
+                        &base_pair,
+                        &quote_pair,
+                        payload.aggregation,
+                        &window,
+                    )
+                    .await?
+                    .0)
+            }
+            _ => Err(PricingError::InvalidRequest(format!(
+                "{} is not derived from {}",
+                payload.pair_id,
+                payload.constituents.join(", ")
+            ))),
+        }
+    }
+
+    /// Aggregate of a pair's own entries, rejected if too stale to trust
+    async fn price_pair(
+        &self,
+        pair_id: &str,
+        aggregation: AggregationMode,
+        window: &Window,
+    ) -> Result<Aggregate, PricingError> {
+        let aggregate = self
+            .redis_client
+            .compute_aggregate(
+                pair_id,
+                window.start,
+                window.end,
+                aggregation,
+                window.end_block,
+                self.config.outlier_filter,
+            )
+            .await
+            .map_err(|e| {
+                PricingError::Internal(e.context(format!("Failed to compute {}", aggregation)))
+            })?
+            .ok_or_else(|| PricingError::NotFound(format!("No data found for pair {}", pair_id)))?;
+
+        self.check_freshness(&aggregate.stats, window)
+            .map_err(|reason| {
+                PricingError::Stale(format!(
+                    "Data for pair {} is too stale: {}",
+                    pair_id, reason
+                ))
+            })?;
+        Ok(aggregate)
+    }
+
+    /// Constituents `BASE/<quote currency>` and `QUOTE/<quote currency>` of `BASE/QUOTE`,
+    /// unless the pair is already quoted in, or based on, the quote currency
+    fn derivation_route(&self, pair_id: &str) -> Option<(String, String)> {
+        let (base, quote) = pair_id.split_once('/')?;
+        let via = &self.config.derivation_quote_currency;
+        if base.is_empty() || quote.is_empty() || base == via || quote == via {
+            return None;
+        }
+        Some((format!("{}/{}", base, via), format!("{}/{}", quote, via)))
+    }
+
+    /// Divides the base price by the quote price over the same window, both in
+    /// the quote currency, and rescales the result to the decimals of `pair_id`
+    async fn derive(
+        &self,
+        pair_id: &str,
+        base_pair: &str,
+        quote_pair: &str,
+        aggregation: AggregationMode,
+        window: &Window,
+    ) -> Result<(BigInt, WindowStats, Vec<ConstituentPrice>), PricingError> {
+        let not_found = |e: PricingError| match e {
+            PricingError::NotFound(_) => PricingError::NotFound(format!(
+                "No data found for pair {}, nor for {} and {} to derive it from",
+                pair_id, base_pair, quote_pair
+            )),
+            e => e,
+        };
+        let base = self
+            .price_pair(base_pair, aggregation, window)
+            .await
+            .map_err(not_found)?;
+        let quote = self
+            .price_pair(quote_pair, aggregation, window)
+            .await
+            .map_err(not_found)?;
+        if quote.value == BigInt::from(0) {
+            return Err(PricingError::Internal(anyhow::anyhow!(
+                "Cannot derive {}: {} is zero",
+                pair_id,
+                quote_pair
+            )));
+        }
+
+        let base_decimals = self.config.decimals_for(base_pair);
+        let quote_decimals = self.config.decimals_for(quote_pair);
+        let decimals = self.config.decimals_for(pair_id);
+        let ten = BigInt::from(10);
+        let value = &base.value * ten.pow(quote_decimals + decimals)
+            / (&quote.value * ten.pow(base_decimals));
+
+        let stats = WindowStats::combine(&base.stats, &quote.stats);
+        let constituents = vec![
+            ConstituentPrice {
+                pair_id: base_pair.to_string(),
+                value: base.value.to_string(),
+                decimals: base_decimals,
+                stats: base.stats,
+            },
+            ConstituentPrice {
+                pair_id: quote_pair.to_string(),
+                value: quote.value.to_string(),
+                decimals: quote_decimals,
+                stats: quote.stats,
+            },
+        ];
+        Ok((value, stats, constituents))
+    }
+
+    /// Per-source or per-publisher view of the aggregate over a window, to spot a
+    /// publisher skewing the price. Not subject to the freshness checks.
+    pub async fn breakdown(
+        &self,
+        pair_id: &str,
+        period: u64,
+        aggregation: AggregationMode,
+        group_by: BreakdownKey,
+        end: WindowEnd,
+    ) -> Result<Breakdown, PricingError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(anyhow::Error::from)?
+            .as_secs();
+        let indexer = self.indexer_state.read().await.clone();
+        let (window_end, end_block) = self.resolve_end(end, now, &indexer).await?;
+        let window_start = window_end.saturating_sub(period);
+
+        let (entries, carried_in) = self
+            .redis_client
+            .get_window(pair_id, window_start, window_end, end_block)
+            .await?;
+        let filtered = self
+            .config
+            .outlier_filter
+            .apply(entries.clone(), carried_in)?;
+        let aggregate = aggregation::aggregate(
+            aggregation,
+            &filtered.entries,
+            filtered.carried_in.as_ref(),
+            window_start,
+            window_end,
+        )
+        .map_err(|e| {
+            PricingError::Internal(e.context(format!("Failed to compute {}", aggregation)))
+        })?
+        .ok_or_else(|| PricingError::NotFound(format!("No data found for pair {}", pair_id)))?;
+
+        let contributions = aggregation::breakdown(
+            aggregation,
+            group_by,
+            &entries,
+            &filtered.rejected,
+            window_start,
+            window_end,
+            &aggregate,
+        )
+        .map_err(|e| {
+            PricingError::Internal(e.context(format!("Failed to compute {}", aggregation)))
+        })?;
+
+        Ok(Breakdown {
+            window_start,
+            window_end,
+            end_block,
+            contributions,
+            aggregate: aggregate.to_string(),
+        })
+    }
+
+    /// Resolves the end of a window to a timestamp, and the block it is anchored
+    /// at for block-based windows. Fails if the indexer has not reached it yet.
+    async fn resolve_end(
+        &self,
+        end: WindowEnd,
+        now: u64,
+        indexer: &IndexerState,
+    ) -> Result<(u64, Option<u64>), PricingError> {
+        let resolved = match end {
+            WindowEnd::Now => (now, None),
+            WindowEnd::Timestamp(timestamp) => {
+                if timestamp > now {
+                    return Err(PricingError::InvalidRequest(format!(
+                        "end_time {} is in the future",
+                        timestamp
+                    )));
+                }
+                if !matches!(indexer.head_block_timestamp, Some(head) if head >= timestamp) {
+                    return Err(PricingError::Stale(format!(
+                        "indexer has not reached end_time {} yet",
+                        timestamp
+                    )));
+                }
+                (timestamp, None)
+            }
+            WindowEnd::Block(block_number) => {
+                if !matches!(indexer.head_block, Some(head) if head >= block_number) {
+                    return Err(PricingError::Stale(format!(
+                        "indexer has not reached block {} yet",
+                        block_number
+                    )));
+                }
+                let (_, timestamp) = self
+                    .redis_client
+                    .get_block_at_or_before(block_number)
+                    .await?
+                    .ok_or_else(|| {
+                        PricingError::NotFound(format!(
+                            "No indexed block at or before {}",
+                            block_number
+                        ))
+                    })?;
+                (timestamp, Some(block_number))
+            }
+        };
+        Ok(resolved)
+    }
+
+    /// Rejects aggregates backed by too few or too old observations, or computed
+    /// while the indexer lags behind the chain. A threshold of 0 disables its check.
+    /// Historical windows are judged against their own end, live ones also need a
+    /// current indexer.
+    fn check_freshness(&self, stats: &WindowStats, window: &Window) -> Result<(), String> {
+        let config = &self.config;
+        if stats.observations < config.min_observations {
+            return Err(format!(
+                "{} observations in the window, at least {} required",
+                stats.observations, config.min_observations
+            ));
+        }
+
+        if config.max_observation_age > 0 {
+            let age = stats
+                .newest_observation
+                .map(|timestamp| window.end.saturating_sub(timestamp));
+            if !matches!(age, Some(age) if age <= config.max_observation_age) {
+                return Err(format!(
+                    "newest observation is older than {}s",
+                    config.max_observation_age
+                ));
+            }
+        }
+
+        if window.live && config.indexer_max_lag > 0 {
+            if let Some(lag) = window.indexer_lag {
+                if lag > config.indexer_max_lag {
+                    return Err(format!("indexer is {}s behind the chain", lag));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,17 +1,270 @@
+use crate::config::{
+    Network, ATTESTATION_TTL, REDIS_KEY_BLOCK_INDEX, REDIS_KEY_BLOCK_TIMESTAMPS,
+    REDIS_KEY_INDEXER_CURSOR, REDIS_KEY_PAIRS, REDIS_KEY_PREFIX_ATTESTATIONS,
+    REDIS_KEY_PREFIX_CANDLES, REDIS_KEY_PREFIX_CHECKPOINT, REDIS_KEY_PREFIX_FUTURE,
+    REDIS_KEY_PREFIX_LEGACY_PAIR_PUBLISHERS, REDIS_KEY_PREFIX_LEGACY_PAIR_SOURCES,
+    REDIS_KEY_PREFIX_PAIR_PUBLISHERS, REDIS_KEY_PREFIX_PAIR_SOURCES, REDIS_KEY_PREFIX_SPOT,
+    REDIS_KEY_PREFIX_SPOT_IDS, REDIS_KEY_PUBLISHER_REGISTRY,
+};
+use crate::services::aggregation::{
+    self, Aggregate, AggregationMode, FilteredWindow, OutlierFilter, WindowStats,
+};
+use crate::services::p2p::TwapMessage;
+use crate::types::candle::{Candle, Resolution};
+use crate::types::checkpoint::Checkpoint;
+use crate::types::future_entry::FutureEntry;
+use crate::types::pair_info::PairInfo;
+use crate::types::publisher_event::PublisherEvent;
 use crate::types::spot_entry::SpotEntry;
-use anyhow::Result;
-use num_bigint::BigInt;
+use anyhow::{Context, Result};
+use apibara_core::node::v1alpha2::Cursor;
 use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Criteria for raw spot entry queries; `None` fields match everything
+#[derive(Debug, Default)]
+pub struct SpotEntryFilter {
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub start_block: Option<u64>,
+    pub end_block: Option<u64>,
+    pub source: Option<String>,
+    pub publisher: Option<String>,
+}
+
+impl SpotEntryFilter {
+    fn matches(&self, entry: &SpotEntry) -> bool {
+        !matches!(self.start_block, Some(block) if entry.block_number < block)
+            && !matches!(self.end_block, Some(block) if entry.block_number > block)
+            && !matches!(&self.source, Some(source) if &entry.source != source)
+            && !matches!(&self.publisher, Some(publisher) if &entry.publisher != publisher)
+    }
+}
+
+/// Position in a pair's entries: `skip` entries at `timestamp` were already returned.
+/// Serialized as `<timestamp>:<skip>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryCursor {
+    pub timestamp: u64,
+    pub skip: usize,
+}
+
+impl fmt::Display for EntryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.timestamp, self.skip)
+    }
+}
+
+impl FromStr for EntryCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (timestamp, skip) = s.split_once(':').context("Malformed cursor")?;
+        Ok(EntryCursor {
+            timestamp: timestamp.parse().context("Malformed cursor")?,
+            skip: skip.parse().context("Malformed cursor")?,
+        })
+    }
+}
+
+// Decrements a member's count in a hash, removing it when it reaches zero
+const RELEASE_SCRIPT: &str = "if redis.call('HINCRBY', KEYS[1], ARGV[1], -1) <= 0 then redis.call('HDEL', KEYS[1], ARGV[1]) end";
 
 #[derive(Clone)]
 pub struct RedisClient {
     client: redis::Client,
+    network: String,
 }
 
 impl RedisClient {
-    pub fn new(redis_url: &str) -> Result<Self> {
+    pub fn new(redis_url: &str, network: &str) -> Result<Self> {
         let client = redis::Client::open(redis_url)?;
-        Ok(RedisClient { client })
+        Ok(RedisClient {
+            client,
+            network: network.to_string(),
+        })
+    }
+
+    /// Prefixes `key` with the network name so data from different networks never mixes
+    fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.network, key)
+    }
+
+    fn spot_key(&self, pair_id: &str) -> String {
+        self.key(&format!("{}{}", REDIS_KEY_PREFIX_SPOT, pair_id))
+    }
+
+    fn pair_sources_key(&self, pair_id: &str) -> String {
+        self.key(&format!("{}{}", REDIS_KEY_PREFIX_PAIR_SOURCES, pair_id))
+    }
+
+    fn pair_publishers_key(&self, pair_id: &str) -> String {
+        self.key(&format!("{}{}", REDIS_KEY_PREFIX_PAIR_PUBLISHERS, pair_id))
+    }
+
+    fn candles_key(&self, resolution: Resolution, pair_id: &str) -> String {
+        self.key(&format!(
+            "{}{}:{}",
+            REDIS_KEY_PREFIX_CANDLES,
+            resolution.as_str(),
+            pair_id
+        ))
+    }
+
+    fn attestations_key(&self, pair_id: &str, period: u64, aggregation: AggregationMode) -> String {
+        self.key(&format!(
+            "{}{}:{}:{}",
+            REDIS_KEY_PREFIX_ATTESTATIONS, pair_id, period, aggregation
+        ))
+    }
+
+    /// Brings data written by older versions up to date. Must run before indexing
+    /// starts.
+    pub async fn migrate(&self) -> Result<()> {
+        self.migrate_unprefixed_keys().await?;
+        for pair_id in self.get_pair_ids().await? {
+            self.migrate_pair_index(&pair_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves data written before keys were prefixed with the network under
+    /// Sepolia, the only network earlier versions indexed, whatever the
+    /// configured network. Keys that already exist there are left untouched.
+    async fn migrate_unprefixed_keys(&self) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        let sepolia = RedisClient {
+            client: self.client.clone(),
+            network: Network::Sepolia.name().to_string(),
+        };
+        let prefixes = [self.key(""), sepolia.key("")];
+        let mut keys = vec![
+            REDIS_KEY_PUBLISHER_REGISTRY.to_string(),
+            REDIS_KEY_INDEXER_CURSOR.to_string(),
+        ];
+        for prefix in [
+            REDIS_KEY_PREFIX_SPOT,
+            REDIS_KEY_PREFIX_FUTURE,
+            REDIS_KEY_PREFIX_CHECKPOINT,
+        ] {
+            for key in self.scan_match(&format!("{}*", prefix)).await? {
+                if !prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+                    keys.push(key);
+                }
+            }
+        }
+
+        for key in keys {
+            let exists: bool = conn.exists(&key).await?;
+            if !exists {
+                continue;
+            }
+            let moved: bool = conn.rename_nx(&key, sepolia.key(&key)).await?;
+            if !moved {
+                eprintln!(
+                    "Not migrating {}: {} already exists",
+                    key,
+                    sepolia.key(&key)
+                );
+                continue;
+            }
+            println!("Migrated {} to {}", key, sepolia.key(&key));
+            // Pairs stored before the pair index existed
+            if let Some(pair_id) = key.strip_prefix(REDIS_KEY_PREFIX_SPOT) {
+                let _: () = conn.sadd(sepolia.key(REDIS_KEY_PAIRS), pair_id).await?;
+            }
+        }
+
+        // Block index members name the keys they were stored in
+        let index: Vec<(String, u64)> =
+            conn.zrange_withscores(REDIS_KEY_BLOCK_INDEX, 0, -1).await?;
+        if index.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (index_member, block_number) in &index {
+            let mut index_member: Vec<String> = serde_json::from_str(index_member)?;
+            // [key, json] optionally followed by [ids key, id]
+            for key in index_member.iter_mut().step_by(2) {
+                *key = sepolia.key(key);
+            }
+            pipe.zadd(
+                sepolia.key(REDIS_KEY_BLOCK_INDEX),
+                serde_json::to_string(&index_member)?,
+                *block_number,
+            )
+            .ignore();
+        }
+        pipe.del(REDIS_KEY_BLOCK_INDEX).ignore();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        println!("Migrated {} block index entries", index.len());
+        Ok(())
+    }
+
+    /// Replaces the legacy sets of a pair's sources and publishers, which were
+    /// never pruned, with counts rebuilt from its raw entries
+    async fn migrate_pair_index(&self, pair_id: &str) -> Result<()> {
+        const BATCH_SIZE: isize = 1000;
+        let mut conn = self.client.get_async_connection().await?;
+        let legacy_keys = [
+            self.key(&format!(
+                "{}{}",
+                REDIS_KEY_PREFIX_LEGACY_PAIR_SOURCES, pair_id
+            )),
+            self.key(&format!(
+                "{}{}",
+                REDIS_KEY_PREFIX_LEGACY_PAIR_PUBLISHERS, pair_id
+            )),
+        ];
+        // Pairs moved from unprefixed keys have entries but no counts yet
+        let (legacy, counted, observations): (usize, bool, u64) = redis::pipe()
+            .exists(&legacy_keys[..])
+            .exists(self.pair_sources_key(pair_id))
+            .zcard(self.spot_key(pair_id))
+            .query_async(&mut conn)
+            .await?;
+        if legacy == 0 && (counted || observations == 0) {
+            return Ok(());
+        }
+
+        let mut sources: HashMap<String, u64> = HashMap::new();
+        let mut publishers: HashMap<String, u64> = HashMap::new();
+        let mut start = 0;
+        loop {
+            let members: Vec<String> = conn
+                .zrange(self.spot_key(pair_id), start, start + BATCH_SIZE - 1)
+                .await?;
+            for json in &members {
+                let entry: SpotEntry = serde_json::from_str(json)?;
+                *sources.entry(entry.source).or_default() += 1;
+                *publishers.entry(entry.publisher).or_default() += 1;
+            }
+            if (members.len() as isize) < BATCH_SIZE {
+                break;
+            }
+            start += BATCH_SIZE;
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, counts) in [
+            (self.pair_sources_key(pair_id), sources),
+            (self.pair_publishers_key(pair_id), publishers),
+        ] {
+            pipe.del(&key).ignore();
+            if !counts.is_empty() {
+                let counts: Vec<(String, u64)> = counts.into_iter().collect();
+                pipe.hset_multiple(&key, &counts).ignore();
+            }
+        }
+        pipe.del(&legacy_keys[..]).ignore();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        println!("Migrated the source and publisher index of {}", pair_id);
+        Ok(())
     }
 
     pub async fn check_connection(&self) -> Result<()> {
@@ -25,24 +278,237 @@ impl RedisClient {
     }
 
     pub async fn store_spot_entry(&self, entry: &SpotEntry) -> Result<()> {
-        let mut conn = self.client.get_async_connection().await?;
-        let key = entry.redis_key();
+        // Store in a sorted set with timestamp as score for easy retrieval
         let json = serde_json::to_string(entry)?;
+        // Keep the pair index up to date so pairs can be listed without scanning keys
+        let on_insert = vec![
+            redis::Cmd::sadd(self.key(REDIS_KEY_PAIRS), &entry.pair_id),
+            redis::Cmd::hincr(self.pair_sources_key(&entry.pair_id), &entry.source, 1),
+            redis::Cmd::hincr(
+                self.pair_publishers_key(&entry.pair_id),
+                &entry.publisher,
+                1,
+            ),
+        ];
+        self.store_indexed(
+            &entry.redis_key(),
+            &json,
+            entry.timestamp_value()?,
+            entry.block_number,
+            Some((&entry.ids_redis_key(), &entry.id())),
+            on_insert,
+        )
+        .await
+    }
 
-        // Store in a sorted set with timestamp as score for easy retrieval
-        conn.zadd(key, &json, entry.timestamp.parse::<f64>()?)
+    pub async fn store_future_entry(&self, entry: &FutureEntry) -> Result<()> {
+        let json = serde_json::to_string(entry)?;
+        let timestamp = entry
+            .timestamp
+            .parse()
+            .with_context(|| format!("Invalid timestamp '{}'", entry.timestamp))?;
+        self.store_indexed(
+            &entry.redis_key(),
+            &json,
+            timestamp,
+            entry.block_number,
+            None,
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// Checkpoints are scored by block number, as the event carries no timestamp
+    pub async fn store_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let json = serde_json::to_string(checkpoint)?;
+        self.store_indexed(
+            &checkpoint.redis_key(),
+            &json,
+            checkpoint.block_number,
+            checkpoint.block_number,
+            None,
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// Publisher registry changes are kept as a log scored by block number
+    pub async fn store_publisher_event(&self, event: &PublisherEvent) -> Result<()> {
+        let json = serde_json::to_string(event)?;
+        self.store_indexed(
+            REDIS_KEY_PUBLISHER_REGISTRY,
+            &json,
+            event.block_number,
+            event.block_number,
+            None,
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// Adds `json` to the sorted set `key` and records the block it came from,
+    /// so it can be rolled back on a reorg.
+    ///
+    /// With an `identity` of (hash key, id), storing is idempotent on the id: the
+    /// hash maps each id to its stored JSON, so storing the same id again is a no-op
+    /// and a different JSON for it replaces the previous member.
+    ///
+    /// `on_insert` runs in the same transaction, unless the id was already stored.
+    async fn store_indexed(
+        &self,
+        key: &str,
+        json: &str,
+        score: u64,
+        block_number: u64,
+        identity: Option<(&str, &str)>,
+        on_insert: Vec<redis::Cmd>,
+    ) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        let key = self.key(key);
+        let mut index_member = vec![key.clone(), json.to_string()];
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut inserted = true;
+        if let Some((ids_key, id)) = identity {
+            let ids_key = self.key(ids_key);
+            let previous: Option<String> = conn.hget(&ids_key, id).await?;
+            match previous {
+                Some(previous) if previous == json => return Ok(()),
+                Some(previous) => {
+                    pipe.zrem(&key, previous).ignore();
+                    inserted = false;
+                }
+                None => {}
+            }
+            pipe.hset(&ids_key, id, json).ignore();
+            index_member.extend([ids_key, id.to_string()]);
+        }
+        if inserted {
+            for cmd in on_insert {
+                pipe.add_command(cmd).ignore();
+            }
+        }
+
+        pipe.zadd(&key, json, score)
+            .ignore()
+            .zadd(
+                self.key(REDIS_KEY_BLOCK_INDEX),
+                serde_json::to_string(&index_member)?,
+                block_number,
+            )
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes every entry stored from a block strictly above `block_number`.
+    /// Returns the number of entries removed.
+    pub async fn rollback_after_block(&self, block_number: u64) -> Result<usize> {
+        let mut conn = self.client.get_async_connection().await?;
+        let index_key = self.key(REDIS_KEY_BLOCK_INDEX);
+        let min = format!("({}", block_number);
+
+        let orphaned: Vec<String> = conn.zrangebyscore(&index_key, &min, "+inf").await?;
+        if orphaned.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for index_member in &orphaned {
+            // [key, json] optionally followed by [ids key, id] for entries with an identity
+            let index_member: Vec<String> = serde_json::from_str(index_member)?;
+            match index_member.as_slice() {
+                [key, json] => {
+                    pipe.zrem(key, json).ignore();
+                }
+                // Only spot entries have an identity
+                [key, json, ids_key, id] => {
+                    pipe.zrem(key, json).ignore().hdel(ids_key, id).ignore();
+                    self.release_spot_entry(&mut pipe, &serde_json::from_str(json)?);
+                }
+                _ => anyhow::bail!("Malformed block index member"),
+            }
+        }
+        pipe.zrembyscore(&index_key, &min, "+inf").ignore();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+
+        Ok(orphaned.len())
+    }
+
+    /// Takes a removed entry's source and publisher off its pair's counts, dropping
+    /// them once no entry is left
+    fn release_spot_entry(&self, pipe: &mut redis::Pipeline, entry: &SpotEntry) {
+        for (key, member) in [
+            (self.pair_sources_key(&entry.pair_id), &entry.source),
+            (self.pair_publishers_key(&entry.pair_id), &entry.publisher),
+        ] {
+            pipe.cmd("EVAL")
+                .arg(RELEASE_SCRIPT)
+                .arg(1)
+                .arg(key)
+                .arg(member)
+                .ignore();
+        }
+    }
+
+    /// Forgets block provenance for blocks below `block_number`; those entries
+    /// can no longer be rolled back.
+    pub async fn prune_block_index(&self, block_number: u64) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        let _: () = conn
+            .zrembyscore(
+                self.key(REDIS_KEY_BLOCK_INDEX),
+                "-inf",
+                format!("({}", block_number),
+            )
             .await?;
         Ok(())
     }
 
+    /// Persists the last processed stream cursor, or clears it when `None`.
+    /// Stored as `<block number>:<hex block hash>`.
+    pub async fn store_cursor(&self, cursor: Option<&Cursor>) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        match cursor {
+            Some(cursor) => {
+                let value = format!("{}:{}", cursor.order_key, hex::encode(&cursor.unique_key));
+                let _: () = conn.set(self.key(REDIS_KEY_INDEXER_CURSOR), value).await?;
+            }
+            None => {
+                let _: () = conn.del(self.key(REDIS_KEY_INDEXER_CURSOR)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get_cursor(&self) -> Result<Option<Cursor>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let value: Option<String> = conn.get(self.key(REDIS_KEY_INDEXER_CURSOR)).await?;
+
+        value
+            .map(|value| {
+                let (order_key, unique_key) = value
+                    .split_once(':')
+                    .context("Malformed stored indexer cursor")?;
+                Ok(Cursor {
+                    order_key: order_key.parse()?,
+                    unique_key: hex::decode(unique_key)?,
+                })
+            })
+            .transpose()
+    }
+
     pub async fn get_spot_entries(
         &self,
         pair_id: &str,
-        start_time: Option<f64>,
-        end_time: Option<f64>,
+        start_time: Option<u64>,
+        end_time: Option<u64>,
     ) -> Result<Vec<SpotEntry>> {
         let mut conn = self.client.get_async_connection().await?;
-        let key = format!("spot:{}", pair_id);
+        let key = self.spot_key(pair_id);
 
         let entries: Vec<String> = match (start_time, end_time) {
             (Some(start), Some(end)) => {
@@ -61,25 +527,540 @@ impl RedisClient {
             .collect()
     }
 
-    pub async fn compute_twap(&self, pair_id: &str, period: u64) -> Result<Option<f64>> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs() as f64;
-        let start_time = now - period as f64;
+    /// Ids of every pair with indexed spot entries, sorted
+    pub async fn get_pair_ids(&self) -> Result<Vec<String>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let mut pair_ids: Vec<String> = conn.smembers(self.key(REDIS_KEY_PAIRS)).await?;
+        pair_ids.sort();
+        Ok(pair_ids)
+    }
+
+    /// Summaries of every indexed pair, sorted by pair id. Pairs whose entries were
+    /// all rolled back are left out.
+    pub async fn get_pairs(&self) -> Result<Vec<PairInfo>> {
+        let pair_ids = self.get_pair_ids().await?;
+        let mut conn = self.client.get_async_connection().await?;
+
+        let mut pairs = Vec::with_capacity(pair_ids.len());
+        for pair_id in pair_ids {
+            let key = self.spot_key(&pair_id);
+            let (observations, first, last, mut sources, mut publishers): (
+                u64,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+            ) = redis::pipe()
+                .zcard(&key)
+                .zrange(&key, 0, 0)
+                .zrange(&key, -1, -1)
+                .hkeys(self.pair_sources_key(&pair_id))
+                .hkeys(self.pair_publishers_key(&pair_id))
+                .query_async(&mut conn)
+                .await?;
+
+            let (Some(first), Some(last)) = (first.first(), last.first()) else {
+                continue;
+            };
+            let first: SpotEntry = serde_json::from_str(first)?;
+            let last: SpotEntry = serde_json::from_str(last)?;
+            sources.sort();
+            publishers.sort();
+
+            pairs.push(PairInfo {
+                pair_id,
+                first_observation: first.timestamp_value()?,
+                last_observation: last.timestamp_value()?,
+                observations,
+                sources,
+                publishers,
+                last_price: last.price,
+            });
+        }
+        Ok(pairs)
+    }
+
+    /// Page of at most `limit` entries of `pair_id` matching `filter`, in timestamp
+    /// order, starting after `cursor`. Returns the cursor of the next page, if any.
+    /// At most `max_scanned` entries are read per call, so a selective filter may
+    /// return a short page with a cursor to continue from.
+    pub async fn query_spot_entries(
+        &self,
+        pair_id: &str,
+        filter: &SpotEntryFilter,
+        cursor: Option<EntryCursor>,
+        limit: usize,
+        max_scanned: usize,
+    ) -> Result<(Vec<SpotEntry>, Option<EntryCursor>)> {
+        const BATCH_SIZE: usize = 256;
+        let mut conn = self.client.get_async_connection().await?;
+        let key = self.spot_key(pair_id);
+        let max = filter
+            .end_time
+            .map(|end| end.to_string())
+            .unwrap_or_else(|| "+inf".to_string());
+
+        // Position as (timestamp, entries already consumed at that timestamp)
+        let mut position = cursor.unwrap_or(EntryCursor {
+            timestamp: filter.start_time.unwrap_or(0),
+            skip: 0,
+        });
+        let mut entries = Vec::new();
+        let mut scanned = 0;
+
+        loop {
+            let batch: Vec<String> = conn
+                .zrangebyscore_limit(
+                    &key,
+                    position.timestamp,
+                    &max,
+                    position.skip as isize,
+                    BATCH_SIZE as isize,
+                )
+                .await?;
 
-        let entries = self
-            .get_spot_entries(pair_id, Some(start_time), Some(now))
+            for json in &batch {
+                let entry: SpotEntry = serde_json::from_str(json)?;
+                let timestamp = entry.timestamp_value()?;
+                if timestamp == position.timestamp {
+                    position.skip += 1;
+                } else {
+                    position = EntryCursor { timestamp, skip: 1 };
+                }
+                scanned += 1;
+
+                if filter.matches(&entry) {
+                    entries.push(entry);
+                }
+                if entries.len() >= limit || scanned >= max_scanned {
+                    return Ok((entries, Some(position)));
+                }
+            }
+
+            if batch.len() < BATCH_SIZE {
+                return Ok((entries, None));
+            }
+        }
+    }
+
+    /// Latest entry strictly before `before`, used to carry a price into a window.
+    /// Entries from blocks after `max_block` are skipped.
+    pub async fn get_last_spot_entry_before(
+        &self,
+        pair_id: &str,
+        before: u64,
+        max_block: Option<u64>,
+    ) -> Result<Option<SpotEntry>> {
+        const PAGE_SIZE: isize = 16;
+        let mut conn = self.client.get_async_connection().await?;
+        let key = self.spot_key(pair_id);
+
+        let mut offset = 0;
+        loop {
+            let entries: Vec<String> = conn
+                .zrevrangebyscore_limit(&key, format!("({}", before), "-inf", offset, PAGE_SIZE)
+                .await?;
+
+            for json in &entries {
+                let entry: SpotEntry = serde_json::from_str(json)?;
+                let too_recent =
+                    matches!(max_block, Some(max_block) if entry.block_number > max_block);
+                if !too_recent {
+                    return Ok(Some(entry));
+                }
+            }
+
+            if entries.len() < PAGE_SIZE as usize {
+                return Ok(None);
+            }
+            offset += PAGE_SIZE;
+        }
+    }
+
+    /// Entries of `pair_id` in the window `[start_time, end_time]` and the last entry
+    /// before it, ignoring entries from blocks after `max_block`
+    pub async fn get_window(
+        &self,
+        pair_id: &str,
+        start_time: u64,
+        end_time: u64,
+        max_block: Option<u64>,
+    ) -> Result<(Vec<SpotEntry>, Option<SpotEntry>)> {
+        let mut entries = self
+            .get_spot_entries(pair_id, Some(start_time), Some(end_time))
+            .await?;
+        if let Some(max_block) = max_block {
+            entries.retain(|entry| entry.block_number <= max_block);
+        }
+        let carried_in = self
+            .get_last_spot_entry_before(pair_id, start_time, max_block)
+            .await?;
+        Ok((entries, carried_in))
+    }
+
+    /// Aggregates the entries of `pair_id` in the window `[start_time, end_time]`,
+    /// ignoring entries from blocks after `max_block` and outliers rejected by `filter`
+    pub async fn compute_aggregate(
+        &self,
+        pair_id: &str,
+        start_time: u64,
+        end_time: u64,
+        mode: AggregationMode,
+        max_block: Option<u64>,
+        filter: OutlierFilter,
+    ) -> Result<Option<Aggregate>> {
+        let (entries, carried_in) = self
+            .get_window(pair_id, start_time, end_time, max_block)
             .await?;
+        let FilteredWindow {
+            entries,
+            carried_in,
+            rejected,
+        } = filter.apply(entries, carried_in)?;
+
+        let value =
+            aggregation::aggregate(mode, &entries, carried_in.as_ref(), start_time, end_time)?;
+        value
+            .map(|value| {
+                Ok(Aggregate {
+                    value,
+                    stats: WindowStats::from_entries(
+                        &entries,
+                        carried_in.as_ref(),
+                        rejected.len(),
+                    )?,
+                })
+            })
+            .transpose()
+    }
+
+    /// Records the timestamp of an indexed block, so historical queries can be
+    /// anchored at a block number
+    pub async fn store_block_timestamp(&self, block_number: u64, timestamp: u64) -> Result<()> {
+        let member = format!("{}:{}", block_number, timestamp);
+        self.store_indexed(
+            REDIS_KEY_BLOCK_TIMESTAMPS,
+            &member,
+            block_number,
+            block_number,
+            None,
+            Vec::new(),
+        )
+        .await
+    }
 
-        if entries.is_empty() {
-            return Ok(None);
+    /// Number and timestamp of the latest indexed block at or below `block_number`
+    pub async fn get_block_at_or_before(&self, block_number: u64) -> Result<Option<(u64, u64)>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let blocks: Vec<String> = conn
+            .zrevrangebyscore_limit(
+                self.key(REDIS_KEY_BLOCK_TIMESTAMPS),
+                block_number,
+                "-inf",
+                0,
+                1,
+            )
+            .await?;
+
+        blocks
+            .into_iter()
+            .next()
+            .map(|member| {
+                let (block_number, timestamp) = member
+                    .split_once(':')
+                    .context("Malformed stored block timestamp")?;
+                Ok((block_number.parse()?, timestamp.parse()?))
+            })
+            .transpose()
+    }
+
+    /// Downsampled candles of `resolution` starting in `[start_time, end_time]`,
+    /// written by the retention task
+    pub async fn get_stored_candles(
+        &self,
+        pair_id: &str,
+        resolution: Resolution,
+        start_time: u64,
+        end_time: u64,
+    ) -> Result<Vec<Candle>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let candles: Vec<String> = conn
+            .zrangebyscore(self.candles_key(resolution, pair_id), start_time, end_time)
+            .await?;
+
+        candles
+            .into_iter()
+            .map(|json| Ok(serde_json::from_str(&json)?))
+            .collect()
+    }
+
+    /// Timestamp of the oldest raw spot entry of `pair_id`
+    pub async fn get_oldest_spot_timestamp(&self, pair_id: &str) -> Result<Option<u64>> {
+        self.get_oldest_score(&self.spot_key(pair_id)).await
+    }
+
+    /// Start of the oldest stored candle of `resolution` for `pair_id`
+    pub async fn get_oldest_candle_start(
+        &self,
+        pair_id: &str,
+        resolution: Resolution,
+    ) -> Result<Option<u64>> {
+        self.get_oldest_score(&self.candles_key(resolution, pair_id))
+            .await
+    }
+
+    async fn get_oldest_score(&self, key: &str) -> Result<Option<u64>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let oldest: Vec<(String, u64)> = conn.zrange_withscores(key, 0, 0).await?;
+        Ok(oldest.into_iter().next().map(|(_, score)| score))
+    }
+
+    /// Block number of the oldest raw spot entry of `pair_id`
+    pub async fn get_oldest_spot_block(&self, pair_id: &str) -> Result<Option<u64>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let oldest: Vec<String> = conn.zrange(self.spot_key(pair_id), 0, 0).await?;
+        oldest
+            .into_iter()
+            .next()
+            .map(|json| Ok(serde_json::from_str::<SpotEntry>(&json)?.block_number))
+            .transpose()
+    }
+
+    /// Forgets the timestamps of blocks below `block_number`.
+    /// Returns the number of blocks removed.
+    pub async fn prune_block_timestamps(&self, block_number: u64) -> Result<usize> {
+        self.trim_below(&self.key(REDIS_KEY_BLOCK_TIMESTAMPS), block_number)
+            .await
+    }
+
+    /// Removes the checkpoints of blocks below `block_number`, keeping the latest
+    /// of each pair. The publisher registry is a single log whose every change is
+    /// needed to know who is registered, so it is never pruned.
+    /// Returns the number of checkpoints removed.
+    pub async fn prune_checkpoints(&self, block_number: u64) -> Result<usize> {
+        let mut removed = 0;
+        for key in self.scan_keys(REDIS_KEY_PREFIX_CHECKPOINT).await? {
+            removed += self.trim_below(&key, block_number).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Removes the future entries timestamped before `timestamp`, keeping the
+    /// latest entry of each contract.
+    /// Returns the number of entries removed.
+    pub async fn prune_future_entries(&self, timestamp: u64) -> Result<usize> {
+        let mut removed = 0;
+        for key in self.scan_keys(REDIS_KEY_PREFIX_FUTURE).await? {
+            removed += self.trim_below(&key, timestamp).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Every key starting with `prefix` in the current network
+    async fn scan_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.scan_match(&format!("{}*", self.key(prefix))).await
+    }
+
+    /// Every key matching the glob `pattern`, in any network
+    async fn scan_match(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let mut iter: redis::AsyncIter<String> = conn.scan_match(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// Removes the members of the sorted set `key` scored below `cutoff`,
+    /// always keeping the latest one
+    async fn trim_below(&self, key: &str, cutoff: u64) -> Result<usize> {
+        let mut conn = self.client.get_async_connection().await?;
+        let latest: Vec<(String, u64)> = conn.zrevrange_withscores(key, 0, 0).await?;
+        let Some((_, latest)) = latest.into_iter().next() else {
+            return Ok(0);
+        };
+        let removed: usize = conn
+            .zrembyscore(key, "-inf", format!("({}", cutoff.min(latest)))
+            .await?;
+        Ok(removed)
+    }
+
+    /// Replaces the raw spot entries of `pair_id` timestamped in `[start_time, end_time)`
+    /// with one-minute candles. `start_time` and `end_time` must be minute aligned.
+    /// Returns the number of entries rolled up.
+    pub async fn roll_up_spot_entries(
+        &self,
+        pair_id: &str,
+        start_time: u64,
+        end_time: u64,
+    ) -> Result<usize> {
+        let mut conn = self.client.get_async_connection().await?;
+        let key = self.spot_key(pair_id);
+        let members: Vec<String> = conn
+            .zrangebyscore(&key, start_time, format!("({}", end_time))
+            .await?;
+        if members.is_empty() {
+            return Ok(0);
+        }
+
+        let entries = members
+            .iter()
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect::<Result<Vec<SpotEntry>>>()?;
+        let candles = aggregation::build_candles(&entries, Resolution::OneMinute.seconds())?;
+
+        self.replace_with_candles(
+            &key,
+            &members,
+            &self.candles_key(Resolution::OneMinute, pair_id),
+            Resolution::OneMinute,
+            start_time..end_time,
+            candles,
+        )
+        .await?;
+
+        let ids: Vec<String> = entries.iter().map(SpotEntry::id).collect();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hdel(
+                self.key(&format!("{}{}", REDIS_KEY_PREFIX_SPOT_IDS, pair_id)),
+                ids,
+            )
+            .ignore();
+        for entry in &entries {
+            self.release_spot_entry(&mut pipe, entry);
+        }
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(members.len())
+    }
+
+    /// Replaces the one-minute candles of `pair_id` starting in `[start_time, end_time)`
+    /// with hourly candles. `start_time` and `end_time` must be hour aligned.
+    /// Returns the number of candles rolled up.
+    pub async fn roll_up_minute_candles(
+        &self,
+        pair_id: &str,
+        start_time: u64,
+        end_time: u64,
+    ) -> Result<usize> {
+        let mut conn = self.client.get_async_connection().await?;
+        let key = self.candles_key(Resolution::OneMinute, pair_id);
+        let members: Vec<String> = conn
+            .zrangebyscore(&key, start_time, format!("({}", end_time))
+            .await?;
+        if members.is_empty() {
+            return Ok(0);
         }
 
-        let sum: f64 = entries
+        let candles = members
+            .iter()
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect::<Result<Vec<Candle>>>()?;
+        let candles = aggregation::downsample_candles(&candles, Resolution::OneHour.seconds())?;
+
+        self.replace_with_candles(
+            &key,
+            &members,
+            &self.candles_key(Resolution::OneHour, pair_id),
+            Resolution::OneHour,
+            start_time..end_time,
+            candles,
+        )
+        .await?;
+        Ok(members.len())
+    }
+
+    /// Atomically removes `members` from `source_key` and merges `candles` into the
+    /// candles of `resolution` stored under `candles_key` that start within `window`
+    async fn replace_with_candles(
+        &self,
+        source_key: &str,
+        members: &[String],
+        candles_key: &str,
+        resolution: Resolution,
+        window: Range<u64>,
+        candles: Vec<Candle>,
+    ) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        let window_end = format!("({}", window.end);
+
+        // Entries published late may land in buckets that were already rolled up
+        let existing: Vec<String> = conn
+            .zrangebyscore(candles_key, window.start, &window_end)
+            .await?;
+        let mut merged = existing
             .iter()
-            .map(|entry| entry.price.parse::<f64>().unwrap_or(0.0))
-            .sum();
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect::<Result<Vec<Candle>>>()?;
+        merged.extend(candles);
+        merged.sort_by_key(|candle| candle.start);
+        let merged = aggregation::downsample_candles(&merged, resolution.seconds())?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zrem(source_key, members)
+            .ignore()
+            .zrembyscore(candles_key, window.start, &window_end)
+            .ignore();
+        for candle in &merged {
+            pipe.zadd(candles_key, serde_json::to_string(candle)?, candle.start)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
 
-        Ok(Some(sum / entries.len() as f64))
+    /// Stores a verified attestation from a peer, scored by its window end.
+    /// Attestations whose window ended more than `ATTESTATION_TTL` ago are dropped.
+    pub async fn store_attestation(&self, message: &TwapMessage) -> Result<()> {
+        let mut conn = self.client.get_async_connection().await?;
+        let payload = &message.payload;
+        let key = self.attestations_key(&payload.pair_id, payload.period, payload.aggregation);
+        let json = serde_json::to_string(message)?;
+        // Trimmed by the local clock, so a peer's window cannot expire others
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        redis::pipe()
+            .atomic()
+            .zadd(&key, json, payload.window_end)
+            .ignore()
+            .zrembyscore(
+                &key,
+                "-inf",
+                format!("({}", now.saturating_sub(ATTESTATION_TTL)),
+            )
+            .ignore()
+            .expire(&key, ATTESTATION_TTL as usize)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Attestations for `pair_id` over `period` seconds whose window ends in `[from, to]`
+    pub async fn get_attestations(
+        &self,
+        pair_id: &str,
+        period: u64,
+        aggregation: AggregationMode,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TwapMessage>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let attestations: Vec<String> = conn
+            .zrangebyscore(
+                self.attestations_key(pair_id, period, aggregation),
+                from,
+                to,
+            )
+            .await?;
+
+        attestations
+            .into_iter()
+            .map(|json| Ok(serde_json::from_str(&json)?))
+            .collect()
     }
 }
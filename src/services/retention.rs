@@ -0,0 +1,134 @@
+use crate::config::RetentionConfig;
+use crate::services::redis_client::RedisClient;
+use crate::types::candle::Resolution;
+use anyhow::Result;
+use std::time::Duration;
+
+// Raw entries are rolled up an hour at a time and minute candles a day at a time,
+// so a single pass never holds a large range in memory or blocks Redis for long
+const RAW_CHUNK: u64 = 3600;
+const MINUTE_CANDLE_CHUNK: u64 = 86400;
+
+/// Periodically rolls old raw spot entries into one-minute candles, and old
+/// one-minute candles into hourly candles. Future entries, checkpoints and block
+/// timestamps older than the raw entries are pruned.
+pub struct RetentionService {
+    config: RetentionConfig,
+    redis_client: RedisClient,
+}
+
+impl RetentionService {
+    pub fn new(config: RetentionConfig, redis_client: RedisClient) -> Self {
+        RetentionService {
+            config,
+            redis_client,
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.compact().await {
+                eprintln!("Retention pass failed: {}", e);
+            }
+        }
+    }
+
+    /// Rolls up every pair once, then prunes the block timestamps and event logs
+    /// past the raw retention
+    async fn compact(&self) -> Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let raw_cutoff = self
+            .config
+            .raw_days
+            .map(|days| align(now.saturating_sub(days * 86400), RAW_CHUNK));
+
+        let mut oldest_block: Option<u64> = None;
+        for pair_id in self.redis_client.get_pair_ids().await? {
+            if let Some(cutoff) = raw_cutoff {
+                let rolled = self.compact_raw(&pair_id, cutoff).await?;
+                if rolled > 0 {
+                    println!("Rolled {} {} entries into minute candles", rolled, pair_id);
+                }
+                if let Some(block) = self.redis_client.get_oldest_spot_block(&pair_id).await? {
+                    oldest_block = Some(oldest_block.map_or(block, |oldest| oldest.min(block)));
+                }
+            }
+            if let Some(days) = self.config.minute_candle_days {
+                let cutoff = align(now.saturating_sub(days * 86400), MINUTE_CANDLE_CHUNK);
+                let rolled = self.compact_minute_candles(&pair_id, cutoff).await?;
+                if rolled > 0 {
+                    println!(
+                        "Rolled {} {} minute candles into hourly candles",
+                        rolled, pair_id
+                    );
+                }
+            }
+        }
+
+        if let Some(cutoff) = raw_cutoff {
+            let pruned = self.redis_client.prune_future_entries(cutoff).await?;
+            if pruned > 0 {
+                println!("Pruned {} future entries", pruned);
+            }
+        }
+        // Block timestamps and checkpoints are only needed as far back as the
+        // oldest raw entry still retained
+        if let Some(block) = oldest_block {
+            let pruned = self.redis_client.prune_block_timestamps(block).await?
+                + self.redis_client.prune_checkpoints(block).await?;
+            if pruned > 0 {
+                println!(
+                    "Pruned {} block timestamps and checkpoints below block {}",
+                    pruned, block
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls up raw entries older than `cutoff`, oldest chunk first
+    async fn compact_raw(&self, pair_id: &str, cutoff: u64) -> Result<usize> {
+        let mut rolled = 0;
+        while let Some(oldest) = self.redis_client.get_oldest_spot_timestamp(pair_id).await? {
+            if oldest >= cutoff {
+                break;
+            }
+            let start = align(oldest, RAW_CHUNK);
+            let end = (start + RAW_CHUNK).min(cutoff);
+            rolled += self
+                .redis_client
+                .roll_up_spot_entries(pair_id, start, end)
+                .await?;
+        }
+        Ok(rolled)
+    }
+
+    /// Rolls up minute candles older than `cutoff`, oldest chunk first
+    async fn compact_minute_candles(&self, pair_id: &str, cutoff: u64) -> Result<usize> {
+        let mut rolled = 0;
+        while let Some(oldest) = self
+            .redis_client
+            .get_oldest_candle_start(pair_id, Resolution::OneMinute)
+            .await?
+        {
+            if oldest >= cutoff {
+                break;
+            }
+            let start = align(oldest, MINUTE_CANDLE_CHUNK);
+            let end = (start + MINUTE_CANDLE_CHUNK).min(cutoff);
+            rolled += self
+                .redis_client
+                .roll_up_minute_candles(pair_id, start, end)
+                .await?;
+        }
+        Ok(rolled)
+    }
+}
+
+fn align(timestamp: u64, chunk: u64) -> u64 {
+    timestamp - timestamp % chunk
+}
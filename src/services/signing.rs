@@ -1,49 +1,234 @@
-use anyhow::{anyhow, Result};
-use num_bigint::BigInt;
-use secp256k1::{Message, Secp256k1, SecretKey};
+use crate::types::twap_payload::{TwapPayload, TWAP_PAYLOAD_VERSION};
+use anyhow::{anyhow, Context, Result};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use starknet::core::crypto::{compute_hash_on_elements, ecdsa_sign, ecdsa_verify, Signature};
+use starknet::core::types::Felt;
+use starknet::core::utils::cairo_short_string_to_felt;
+use starknet::signers::SigningKey;
+use std::fmt;
+use std::str::FromStr;
+
+/// Signature scheme used for signed payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningScheme {
+    /// secp256k1 ECDSA over the SHA-256 hash of the canonical message, DER encoded
+    #[default]
+    Secp256k1,
+    /// STARK-curve ECDSA over the Pedersen hash chain of the payload felts,
+    /// verifiable from a Cairo contract
+    Stark,
+}
+
+impl FromStr for SigningScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "secp256k1" => Ok(SigningScheme::Secp256k1),
+            "stark" => Ok(SigningScheme::Stark),
+            other => Err(anyhow!("Unknown signing scheme: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for SigningScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningScheme::Secp256k1 => f.write_str("secp256k1"),
+            SigningScheme::Stark => f.write_str("stark"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Signer {
+    Secp256k1 {
+        secp: Secp256k1<secp256k1::All>,
+        secret_key: SecretKey,
+    },
+    Stark {
+        private_key: Felt,
+    },
+}
 
 #[derive(Clone)]
 pub struct SigningService {
-    secp: Secp256k1<secp256k1::All>,
-    secret_key: SecretKey,
+    signer: Signer,
     public_key: String,
 }
 
 impl SigningService {
-    pub fn new(private_key_hex: &str) -> Result<Self> {
-        let secp = Secp256k1::new();
+    pub fn new(private_key_hex: &str, scheme: SigningScheme) -> Result<Self> {
         let clean_key = private_key_hex.trim_start_matches("0x");
 
-        let secret_key = SecretKey::from_slice(&hex::decode(clean_key)?)
-            .map_err(|e| anyhow!("Invalid private key: {}", e))?;
-        let public_key =
-            hex::encode(secp256k1::PublicKey::from_secret_key(&secp, &secret_key).serialize());
+        match scheme {
+            SigningScheme::Secp256k1 => {
+                let secp = Secp256k1::new();
+                let secret_key = SecretKey::from_slice(&hex::decode(clean_key)?)
+                    .map_err(|e| anyhow!("Invalid private key: {}", e))?;
+                let public_key = hex::encode(
+                    secp256k1::PublicKey::from_secret_key(&secp, &secret_key).serialize(),
+                );
 
-        Ok(SigningService {
-            secp,
-            secret_key,
-            public_key,
-        })
+                Ok(SigningService {
+                    signer: Signer::Secp256k1 { secp, secret_key },
+                    public_key,
+                })
+            }
+            SigningScheme::Stark => {
+                let private_key = felt_from_hex(clean_key).context("Invalid STARK private key")?;
+                let public_key = SigningKey::from_secret_scalar(private_key)
+                    .verifying_key()
+                    .scalar();
+
+                Ok(SigningService {
+                    signer: Signer::Stark { private_key },
+                    public_key: hex::encode(public_key.to_bytes_be()),
+                })
+            }
+        }
     }
 
     pub fn get_public_key(&self) -> &str {
         &self.public_key
     }
 
-    pub fn sign_twap(&self, twap: f64) -> Result<String> {
-        let twap_bigint = BigInt::from((twap) as u64);
+    pub fn scheme(&self) -> SigningScheme {
+        match self.signer {
+            Signer::Secp256k1 { .. } => SigningScheme::Secp256k1,
+            Signer::Stark { .. } => SigningScheme::Stark,
+        }
+    }
 
-        let twap_str = twap_bigint.to_string();
+    /// Hex-encoded signature over `payload`. For the STARK scheme this is `r`
+    /// followed by `s`, each as 32 big-endian bytes.
+    pub fn sign_payload(&self, payload: &TwapPayload) -> Result<String> {
+        match &self.signer {
+            Signer::Secp256k1 { secp, secret_key } => {
+                let message = Message::from_slice(&sha256_message_hash(payload))?;
+                let signature = secp.sign_ecdsa(&message, secret_key);
 
-        let mut hasher = Sha256::new();
-        hasher.update(twap_str.as_bytes());
-        let message_hash = hasher.finalize();
+                Ok(hex::encode(signature.serialize_der()))
+            }
+            Signer::Stark { private_key } => {
+                let message_hash = stark_message_hash(payload)?;
+                let signature = ecdsa_sign(private_key, &message_hash)
+                    .map_err(|e| anyhow!("Failed to sign payload: {}", e))?;
 
-        let message = Message::from_slice(&message_hash)?;
+                Ok(format!(
+                    "{}{}",
+                    hex::encode(signature.r.to_bytes_be()),
+                    hex::encode(signature.s.to_bytes_be())
+                ))
+            }
+        }
+    }
 
-        let signature = self.secp.sign_ecdsa(&message, &self.secret_key);
+    /// Hex-encoded hash of `payload` that this service signs
+    pub fn message_hash(&self, payload: &TwapPayload) -> Result<String> {
+        message_hash(payload, self.scheme())
+    }
+
+    /// Checks a hex-encoded `signature` over `payload` against a hex-encoded public key
+    pub fn verify_payload(
+        payload: &TwapPayload,
+        scheme: SigningScheme,
+        signature: &str,
+        public_key: &str,
+    ) -> Result<()> {
+        if payload.version != TWAP_PAYLOAD_VERSION {
+            return Err(anyhow!("Unsupported payload version {}", payload.version));
+        }
+
+        match scheme {
+            SigningScheme::Secp256k1 => {
+                let secp = Secp256k1::verification_only();
+                let message = Message::from_slice(&sha256_message_hash(payload))?;
+                let signature = secp256k1::ecdsa::Signature::from_der(&hex::decode(signature)?)?;
+                let public_key = PublicKey::from_slice(&hex::decode(public_key)?)?;
+
+                secp.verify_ecdsa(&message, &signature, &public_key)
+                    .map_err(|e| anyhow!("Invalid signature: {}", e))
+            }
+            SigningScheme::Stark => {
+                let message_hash = stark_message_hash(payload)?;
+                if signature.len() != 128 {
+                    return Err(anyhow!("Invalid STARK signature length"));
+                }
+                let (r, s) = signature.split_at(64);
+                let signature = Signature {
+                    r: felt_from_hex(r)?,
+                    s: felt_from_hex(s)?,
+                };
+                let public_key = felt_from_hex(public_key.trim_start_matches("0x"))?;
+
+                match ecdsa_verify(&public_key, &message_hash, &signature) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(anyhow!("Invalid signature")),
+                    Err(e) => Err(anyhow!("Invalid signature: {}", e)),
+                }
+            }
+        }
+    }
+}
+
+/// Hex-encoded hash of `payload` as signed under `scheme`
+pub fn message_hash(payload: &TwapPayload, scheme: SigningScheme) -> Result<String> {
+    match scheme {
+        SigningScheme::Secp256k1 => Ok(hex::encode(sha256_message_hash(payload))),
+        SigningScheme::Stark => Ok(hex::encode(stark_message_hash(payload)?.to_bytes_be())),
+    }
+}
+
+fn sha256_message_hash(payload: &TwapPayload) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(payload.canonical_message().as_bytes());
+    hasher.finalize().into()
+}
+
+/// Pedersen hash chain (`compute_hash_on_elements`) over the payload fields in
+/// canonical order, with strings encoded as Cairo short strings
+fn stark_message_hash(payload: &TwapPayload) -> Result<Felt> {
+    let mut elements = vec![
+        cairo_short_string_to_felt("pragma-twap")?,
+        Felt::from(payload.version),
+        cairo_short_string_to_felt(&payload.network)?,
+        cairo_short_string_to_felt(&payload.pair_id)
+            .with_context(|| format!("Pair id {} is not a short string", payload.pair_id))?,
+        Felt::from(payload.period),
+        Felt::from(payload.window_start),
+        Felt::from(payload.window_end),
+        Felt::from(payload.timestamp),
+        cairo_short_string_to_felt(payload.aggregation.as_str())?,
+        Felt::from(payload.decimals),
+        Felt::from_dec_str(&payload.twap)
+            .map_err(|e| anyhow!("Value {} is not a felt: {}", payload.twap, e))?,
+        Felt::from(payload.constituents.len()),
+    ];
+    for constituent in &payload.constituents {
+        elements.push(
+            cairo_short_string_to_felt(constituent)
+                .with_context(|| format!("Pair id {} is not a short string", constituent))?,
+        );
+    }
+
+    Ok(compute_hash_on_elements(&elements))
+}
 
-        Ok(hex::encode(signature.serialize_der()))
+/// Parses a hex felt, with or without `0x`. Values of odd length are accepted,
+/// values that do not fit in the field are rejected rather than reduced.
+fn felt_from_hex(value: &str) -> Result<Felt> {
+    let felt = Felt::from_hex(value).map_err(|e| anyhow!("Invalid hex value: {}", e))?;
+    // from_hex reduces modulo the field prime, which would change the value
+    let digits = value.trim_start_matches("0x").trim_start_matches('0');
+    if !format!("{:x}", felt)
+        .trim_start_matches('0')
+        .eq_ignore_ascii_case(digits)
+    {
+        return Err(anyhow!("Value does not fit in a felt"));
     }
+    Ok(felt)
 }
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// Width of a candle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::OneMinute => "1m",
+            Resolution::FiveMinutes => "5m",
+            Resolution::OneHour => "1h",
+            Resolution::OneDay => "1d",
+        }
+    }
+
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Resolution::OneMinute => 60,
+            Resolution::FiveMinutes => 300,
+            Resolution::OneHour => 3600,
+            Resolution::OneDay => 86400,
+        }
+    }
+}
+
+/// OHLCV candle over `[start, start + resolution)`, prices as fixed-point integers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Candle {
+    pub start: u64,
+    pub open: String,
+    pub high: String,
+    pub low: String,
+    pub close: String,
+    pub volume: String,
+    pub observations: usize,
+}
@@ -0,0 +1,44 @@
+use crate::config::REDIS_KEY_PREFIX_CHECKPOINT;
+use crate::types::spot_entry::int_to_ascii;
+use apibara_core::starknet::v1alpha2::Event;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+
+/// On-chain checkpoint of a spot pair (`CheckpointSpotEntry`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub pair_id: String,
+    pub aggregation_mode: String,
+    pub block_number: u64,
+}
+
+impl Checkpoint {
+    pub fn from_event(event: &Event, block_number: u64) -> Option<Self> {
+        if event.data.len() < 2 {
+            return None;
+        }
+
+        let pair_id = int_to_ascii(&Felt::from_bytes_be(&event.data[0].to_bytes()).to_bigint());
+        // The Cairo `AggregationMode` enum is serialized as its variant index
+        let mode = Felt::from_bytes_be(&event.data[1].to_bytes());
+        let aggregation_mode = if mode == Felt::ZERO {
+            "median".to_string()
+        } else if mode == Felt::ONE {
+            "mean".to_string()
+        } else if mode == Felt::TWO {
+            "conversion_rate".to_string()
+        } else {
+            format!("unknown({})", mode)
+        };
+
+        Some(Checkpoint {
+            pair_id: pair_id.unwrap_or_default(),
+            aggregation_mode,
+            block_number,
+        })
+    }
+
+    pub fn redis_key(&self) -> String {
+        format!("{}{}", REDIS_KEY_PREFIX_CHECKPOINT, self.pair_id)
+    }
+}
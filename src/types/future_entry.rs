@@ -0,0 +1,51 @@
+use crate::config::REDIS_KEY_PREFIX_FUTURE;
+use crate::types::spot_entry::int_to_ascii;
+use apibara_core::starknet::v1alpha2::Event;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FutureEntry {
+    pub timestamp: String,
+    pub source: String,
+    pub publisher: String,
+    pub price: String,
+    pub pair_id: String,
+    pub volume: String,
+    pub expiration_timestamp: String,
+    pub block_number: u64,
+}
+
+impl FutureEntry {
+    pub fn from_event(event: &Event, block_number: u64) -> Option<Self> {
+        if event.data.len() < 7 {
+            return None;
+        }
+
+        let timestamp = Felt::from_bytes_be(&event.data[0].to_bytes()).to_bigint();
+        let source = int_to_ascii(&Felt::from_bytes_be(&event.data[1].to_bytes()).to_bigint());
+        let publisher = int_to_ascii(&Felt::from_bytes_be(&event.data[2].to_bytes()).to_bigint());
+        let price = Felt::from_bytes_be(&event.data[3].to_bytes()).to_bigint();
+        let pair_id = int_to_ascii(&Felt::from_bytes_be(&event.data[4].to_bytes()).to_bigint());
+        let volume = Felt::from_bytes_be(&event.data[5].to_bytes()).to_bigint();
+        let expiration_timestamp = Felt::from_bytes_be(&event.data[6].to_bytes()).to_bigint();
+
+        Some(FutureEntry {
+            timestamp: timestamp.to_string(),
+            source: source.unwrap_or_default(),
+            publisher: publisher.unwrap_or_default(),
+            price: price.to_string(),
+            pair_id: pair_id.unwrap_or_default(),
+            volume: volume.to_string(),
+            expiration_timestamp: expiration_timestamp.to_string(),
+            block_number,
+        })
+    }
+
+    pub fn redis_key(&self) -> String {
+        format!(
+            "{}{}:{}",
+            REDIS_KEY_PREFIX_FUTURE, self.pair_id, self.expiration_timestamp
+        )
+    }
+}
@@ -1 +1,7 @@
+pub mod candle;
+pub mod checkpoint;
+pub mod future_entry;
+pub mod pair_info;
+pub mod publisher_event;
 pub mod spot_entry;
+pub mod twap_payload;
@@ -0,0 +1,14 @@
+use serde::Serialize;
+
+/// Summary of the spot entries indexed for a pair
+#[derive(Debug, Clone, Serialize)]
+pub struct PairInfo {
+    pub pair_id: String,
+    pub first_observation: u64,
+    pub last_observation: u64,
+    pub observations: u64,
+    /// Sources and publishers that have published an entry for the pair
+    pub sources: Vec<String>,
+    pub publishers: Vec<String>,
+    pub last_price: String,
+}
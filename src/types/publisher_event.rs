@@ -0,0 +1,58 @@
+use crate::config::{
+    DELETED_PUBLISHER_SELECTOR, REGISTERED_PUBLISHER_SELECTOR, UPDATED_PUBLISHER_ADDRESS_SELECTOR,
+};
+use crate::types::spot_entry::int_to_ascii;
+use apibara_core::starknet::v1alpha2::{Event, FieldElement};
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublisherAction {
+    Registered,
+    AddressUpdated,
+    Deleted,
+}
+
+/// Change emitted by the Pragma publisher registry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublisherEvent {
+    pub action: PublisherAction,
+    pub publisher: String,
+    pub publisher_address: Option<String>,
+    pub previous_address: Option<String>,
+    pub block_number: u64,
+}
+
+impl PublisherEvent {
+    pub fn from_event(event: &Event, block_number: u64) -> Option<Self> {
+        let selector = Felt::from_bytes_be(&event.keys.first()?.to_bytes());
+        let field = |i: usize| event.data.get(i).map(FieldElement::to_bytes);
+        let publisher = int_to_ascii(&Felt::from_bytes_be(&field(0)?).to_bigint());
+        let address =
+            |i: usize| field(i).map(|bytes| format!("{:#x}", Felt::from_bytes_be(&bytes)));
+
+        let (action, publisher_address, previous_address) =
+            if selector == Felt::from_hex(REGISTERED_PUBLISHER_SELECTOR).ok()? {
+                (PublisherAction::Registered, Some(address(1)?), None)
+            } else if selector == Felt::from_hex(UPDATED_PUBLISHER_ADDRESS_SELECTOR).ok()? {
+                (
+                    PublisherAction::AddressUpdated,
+                    Some(address(2)?),
+                    Some(address(1)?),
+                )
+            } else if selector == Felt::from_hex(DELETED_PUBLISHER_SELECTOR).ok()? {
+                (PublisherAction::Deleted, None, None)
+            } else {
+                return None;
+            };
+
+        Some(PublisherEvent {
+            action,
+            publisher: publisher.unwrap_or_default(),
+            publisher_address,
+            previous_address,
+            block_number,
+        })
+    }
+}
@@ -1,9 +1,11 @@
+use crate::config::{REDIS_KEY_PREFIX_SPOT, REDIS_KEY_PREFIX_SPOT_IDS};
+use anyhow::{Context, Result};
 use apibara_core::starknet::v1alpha2::{Event, FieldElement};
 use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
 use starknet::core::types::Felt;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpotEntry {
     pub timestamp: String,
     pub source: String,
@@ -12,10 +14,16 @@ pub struct SpotEntry {
     pub pair_id: String,
     pub volume: String,
     pub block_number: u64,
+    /// Hash of the transaction that emitted the event, empty for entries stored
+    /// before it was recorded
+    #[serde(default)]
+    pub transaction_hash: String,
+    #[serde(default)]
+    pub event_index: u64,
 }
 
 impl SpotEntry {
-    pub fn from_event(event: &Event, block_number: u64) -> Option<Self> {
+    pub fn from_event(event: &Event, block_number: u64, transaction_hash: &str) -> Option<Self> {
         if event.data.len() < 6 {
             return None;
         }
@@ -35,15 +43,52 @@ impl SpotEntry {
             pair_id: pair_id.unwrap_or_default(),
             volume: volume.to_string(),
             block_number,
+            transaction_hash: transaction_hash.to_string(),
+            event_index: event.index,
         })
     }
 
+    /// Price as an exact fixed-point integer, scaled by the pair's decimals
+    pub fn price_value(&self) -> Result<BigInt> {
+        self.price
+            .parse()
+            .with_context(|| format!("Invalid price '{}' for {}", self.price, self.pair_id))
+    }
+
+    pub fn volume_value(&self) -> Result<BigInt> {
+        self.volume
+            .parse()
+            .with_context(|| format!("Invalid volume '{}' for {}", self.volume, self.pair_id))
+    }
+
+    pub fn timestamp_value(&self) -> Result<u64> {
+        self.timestamp.parse().with_context(|| {
+            format!(
+                "Invalid timestamp '{}' for {}",
+                self.timestamp, self.pair_id
+            )
+        })
+    }
+
+    /// Stable identity of the on-chain event the entry was decoded from
+    pub fn id(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.block_number, self.transaction_hash, self.event_index
+        )
+    }
+
     pub fn redis_key(&self) -> String {
-        format!("spot:{}", self.pair_id)
+        format!("{}{}", REDIS_KEY_PREFIX_SPOT, self.pair_id)
+    }
+
+    /// Hash mapping entry ids to their stored JSON
+    pub fn ids_redis_key(&self) -> String {
+        format!("{}{}", REDIS_KEY_PREFIX_SPOT_IDS, self.pair_id)
     }
 }
 
-fn int_to_ascii(num: &BigInt) -> Option<String> {
+pub(crate) fn int_to_ascii(num: &BigInt) -> Option<String> {
     let bytes = num.to_bytes_be().1;
     String::from_utf8(bytes.into_iter().filter(|&byte| byte.is_ascii()).collect()).ok()
 }
@@ -0,0 +1,56 @@
+use crate::services::aggregation::AggregationMode;
+use serde::{Deserialize, Serialize};
+
+pub const TWAP_PAYLOAD_VERSION: u8 = 3;
+
+/// Everything a signature over an aggregated price commits to. Signing the
+/// canonical encoding of the whole payload, rather than the bare value, keeps a
+/// signature from being replayed for another pair, period, window or method.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TwapPayload {
+    pub version: u8,
+    pub network: String,
+    pub pair_id: String,
+    pub period: u64,
+    pub window_start: u64,
+    pub window_end: u64,
+    pub timestamp: u64,
+    pub aggregation: AggregationMode,
+    pub decimals: u32,
+    pub twap: String,
+    /// Pairs the price was derived from, empty for a pair priced directly
+    #[serde(default)]
+    pub constituents: Vec<String>,
+}
+
+impl TwapPayload {
+    pub fn is_derived(&self) -> bool {
+        !self.constituents.is_empty()
+    }
+
+    /// Canonical byte encoding that gets hashed and signed:
+    /// `pragma-twap:v<version>|<network>|<len>:<pair_id>|<period>|<window_start>|<window_end>|<timestamp>|<aggregation>|<decimals>|<twap>|<count>`,
+    /// followed by `|<len>:<pair_id>` for each constituent of a derived price.
+    /// Pair ids are length-prefixed so no pair id can forge a field boundary.
+    pub fn canonical_message(&self) -> String {
+        let mut message = format!(
+            "pragma-twap:v{}|{}|{}:{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            self.version,
+            self.network,
+            self.pair_id.len(),
+            self.pair_id,
+            self.period,
+            self.window_start,
+            self.window_end,
+            self.timestamp,
+            self.aggregation,
+            self.decimals,
+            self.twap,
+            self.constituents.len(),
+        );
+        for constituent in &self.constituents {
+            message.push_str(&format!("|{}:{}", constituent.len(), constituent));
+        }
+        message
+    }
+}